@@ -0,0 +1,93 @@
+//! Compares the `ProxyStream` read buffer against the copying approach it
+//! replaced, and reports how many frames the write buffer sends. Run with
+//! `cargo bench --bench buffer`.
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/proxy/buffer.rs"]
+mod buffer;
+
+use buffer::{ReadBuffer, WriteBuffer};
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+const TOTAL: usize = 512 * 1024 * 1024;
+const READ_SIZE: usize = 16 * 1024;
+
+fn report(name: &str, elapsed: Duration) {
+    let mbps = TOTAL as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+    println!("{:<40} {:>10.1?} {:>10.0} MiB/s", name, elapsed, mbps);
+}
+
+/// Frames as they come out of the websocket, already owned.
+fn frames(size: usize) -> Vec<Vec<u8>> {
+    (0..TOTAL / size).map(|i| vec![i as u8; size]).collect()
+}
+
+fn read_copying(frames: Vec<Vec<u8>>) -> Duration {
+    let mut out = vec![0u8; READ_SIZE];
+    let start = Instant::now();
+    let mut buffer = BytesMut::new();
+    for frame in frames {
+        buffer.put_slice(&frame);
+        while !buffer.is_empty() {
+            let n = buffer.len().min(out.len());
+            out[..n].copy_from_slice(&buffer.split_to(n));
+            black_box(&out);
+        }
+    }
+    start.elapsed()
+}
+
+fn read_queue(frames: Vec<Vec<u8>>) -> Duration {
+    let mut out = vec![0u8; READ_SIZE];
+    let start = Instant::now();
+    let mut buffer = ReadBuffer::new(4 * 1024 * 1024);
+    for frame in frames {
+        buffer.push(Bytes::from(frame)).unwrap();
+        while let Some(chunk) = buffer.take(out.len()) {
+            out[..chunk.len()].copy_from_slice(&chunk);
+            black_box(&out);
+        }
+    }
+    start.elapsed()
+}
+
+fn write_coalescing(size: usize) -> (Duration, usize) {
+    let data = vec![7u8; size];
+    let mut sent = 0;
+    let start = Instant::now();
+    let mut buffer = WriteBuffer::new(64 * 1024, usize::MAX);
+    for _ in 0..TOTAL / size {
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let n = buffer.push(rest);
+            rest = &rest[n..];
+            if let Some(frame) = buffer.full_frame() {
+                black_box(frame.to_vec());
+                sent += 1;
+            }
+        }
+    }
+    if let Some(frame) = buffer.frame() {
+        black_box(frame.to_vec());
+        sent += 1;
+    }
+    (start.elapsed(), sent)
+}
+
+fn main() {
+    for size in [1024, 16 * 1024, 64 * 1024] {
+        report(&format!("read {}B frames, copying", size), read_copying(frames(size)));
+        report(&format!("read {}B frames, frame queue", size), read_queue(frames(size)));
+    }
+
+    // every sent frame is a message crossing into js, where the cost lies;
+    // writing a frame per call would send TOTAL / size of them
+    for size in [512, 4 * 1024, 16 * 1024] {
+        let (elapsed, sent) = write_coalescing(size);
+        report(&format!("write {}B coalesced, {} frames", size, sent), elapsed);
+    }
+}
@@ -0,0 +1,79 @@
+//! Embeds the geoip and geosite lists under `data/` when the `geoip` and
+//! `geosite` features are enabled. Geoip lists are packed into a binary
+//! form of `[family, prefix, address...]` records to keep the wasm small.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+fn lists(dir: &str) -> Vec<(String, PathBuf)> {
+    let mut lists: Vec<_> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|x| x.ok().map(|x| x.path()))
+                .filter(|x| x.extension().is_some_and(|ext| ext == "txt"))
+                .map(|x| (x.file_stem().unwrap().to_string_lossy().to_string(), x))
+                .collect()
+        })
+        .unwrap_or_default();
+    lists.sort();
+    lists
+}
+
+fn pack_cidrs(text: &str, path: &Path) -> Vec<u8> {
+    let mut packed = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = || -> ! { panic!("{}: invalid cidr {}", path.display(), line) };
+        let (addr, prefix) = line.split_once('/').unwrap_or_else(|| invalid());
+        let addr: IpAddr = addr.parse().unwrap_or_else(|_| invalid());
+        let prefix: u8 = prefix.parse().unwrap_or_else(|_| invalid());
+        match addr {
+            IpAddr::V4(ip) if prefix <= 32 => {
+                packed.extend_from_slice(&[4, prefix]);
+                packed.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) if prefix <= 128 => {
+                packed.extend_from_slice(&[6, prefix]);
+                packed.extend_from_slice(&ip.octets());
+            }
+            _ => invalid(),
+        }
+    }
+    packed
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=data");
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let mut code = String::new();
+
+    code.push_str("pub const GEOIP: &[(&str, &[u8])] = &[\n");
+    if env::var_os("CARGO_FEATURE_GEOIP").is_some() {
+        for (name, path) in lists("data/geoip") {
+            let packed = pack_cidrs(&fs::read_to_string(&path).unwrap(), &path);
+            let bin = out.join(format!("geoip-{}.bin", name));
+            fs::write(&bin, packed).unwrap();
+            writeln!(code, "    ({:?}, include_bytes!({:?})),", name, bin).unwrap();
+        }
+    }
+    code.push_str("];\n");
+
+    code.push_str("pub const GEOSITE: &[(&str, &str)] = &[\n");
+    if env::var_os("CARGO_FEATURE_GEOSITE").is_some() {
+        for (name, path) in lists("data/geosite") {
+            let path = fs::canonicalize(path).unwrap();
+            writeln!(code, "    ({:?}, include_str!({:?})),", name, path).unwrap();
+        }
+    }
+    code.push_str("];\n");
+
+    fs::write(out.join("geo_data.rs"), code).unwrap();
+}
@@ -0,0 +1,154 @@
+#!/usr/bin/env python3
+"""Converts entries of v2ray geoip.dat / geosite.dat into the text lists
+embedded from data/geoip and data/geosite.
+
+    scripts/geo2txt.py geoip geoip.dat cn private
+    scripts/geo2txt.py geosite geosite.dat category-ads netflix
+
+The source may also be an http(s) url, or `latest` for the current v2fly
+release, and for geosite a checkout of domain-list-community's `data`
+directory, whose `include:` lines are flattened:
+
+    scripts/geo2txt.py geoip latest cn ir
+    scripts/geo2txt.py geosite ~/domain-list-community/data netflix
+"""
+import ipaddress
+import sys
+import urllib.request
+from pathlib import Path
+
+LATEST = {
+    "geoip": "https://github.com/v2fly/geoip/releases/latest/download/geoip.dat",
+    "geosite": "https://github.com/v2fly/domain-list-community/releases/latest/download/dlc.dat",
+}
+
+
+def fields(buf):
+    """Yields (field number, value) of a protobuf message."""
+    i = 0
+
+    def varint():
+        nonlocal i
+        shift = result = 0
+        while True:
+            b = buf[i]
+            i += 1
+            result |= (b & 0x7F) << shift
+            if b < 0x80:
+                return result
+            shift += 7
+
+    while i < len(buf):
+        key = varint()
+        kind = key & 7
+        if kind == 0:
+            yield key >> 3, varint()
+        elif kind == 2:
+            n = varint()
+            yield key >> 3, buf[i : i + n]
+            i += n
+        elif kind == 1:
+            i += 8
+        elif kind == 5:
+            i += 4
+        else:
+            raise ValueError(f"unsupported wire type {kind}")
+
+
+def read(kind, source):
+    if source == "latest":
+        source = LATEST[kind]
+    if source.startswith(("http://", "https://")):
+        with urllib.request.urlopen(source) as resp:
+            return resp.read()
+    return Path(source).read_bytes()
+
+
+def entries(buf, wanted):
+    for num, entry in fields(buf):
+        if num != 1:
+            continue
+        body = list(fields(entry))
+        code = next(v for n, v in body if n == 1).decode().lower()
+        if code in wanted:
+            yield code, body
+
+
+def geoip(body):
+    lines = []
+    for num, cidr in body:
+        if num != 2:
+            continue
+        cidr = dict(fields(cidr))
+        addr = ipaddress.ip_address(bytes(cidr[1]))
+        lines.append(f"{addr}/{cidr.get(2, 0)}")
+    return lines
+
+
+def geosite(body):
+    prefixes = {0: "keyword:", 1: "regexp:", 2: "", 3: "full:"}
+    lines = []
+    for num, domain in body:
+        if num != 2:
+            continue
+        domain = list(fields(domain))
+        kind = next((v for n, v in domain if n == 1), 0)
+        value = next(v for n, v in domain if n == 2).decode()
+        lines.append(prefixes[kind] + value)
+    return lines
+
+
+def source_rules(data, name, seen=()):
+    """Yields the rules of a domain-list-community source file, with its
+    `include:` lines replaced by the included rules. `include:x @attr` keeps
+    the rules tagged @attr, and `@-attr` those that are not."""
+    if name in seen:
+        raise ValueError(f"include cycle: {' -> '.join(seen + (name,))}")
+    path = Path(data) / name
+    if not path.is_file():
+        raise ValueError(f"missing include: {name}")
+
+    for line in path.read_text().splitlines():
+        line = line.split("#")[0].strip()
+        if not line:
+            continue
+        rule, *attrs = line.split()
+        if not rule.startswith("include:"):
+            yield rule, {x[1:] for x in attrs if x.startswith("@")}
+            continue
+
+        want = {x[1:] for x in attrs if x.startswith("@") and not x.startswith("@-")}
+        skip = {x[2:] for x in attrs if x.startswith("@-")}
+        for included, tags in source_rules(data, rule[len("include:"):], seen + (name,)):
+            if want <= tags and not skip & tags:
+                yield included, tags
+
+
+def main():
+    if len(sys.argv) < 4 or sys.argv[1] not in ("geoip", "geosite"):
+        sys.exit(__doc__)
+
+    kind, path, wanted = sys.argv[1], sys.argv[2], {x.lower() for x in sys.argv[3:]}
+    out = Path(__file__).resolve().parent.parent / "data" / kind
+    origin = Path(LATEST[kind] if path == "latest" else path).name
+
+    def write(code, lines):
+        (out / f"{code}.txt").write_text(f"# converted from {origin}\n" + "\n".join(lines) + "\n")
+        print(f"{kind}:{code}: {len(lines)} entries")
+        wanted.discard(code)
+
+    if kind == "geosite" and Path(path).is_dir():
+        for code in sorted(wanted):
+            if (Path(path) / code).is_file():
+                write(code, list(dict.fromkeys(rule for rule, _ in source_rules(path, code))))
+    else:
+        convert = geoip if kind == "geoip" else geosite
+        for code, body in entries(read(kind, path), wanted):
+            write(code, convert(body))
+
+    if wanted:
+        sys.exit(f"not found: {', '.join(sorted(wanted))}")
+
+
+if __name__ == "__main__":
+    main()
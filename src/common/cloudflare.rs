@@ -0,0 +1,34 @@
+use super::cidr::Cidr;
+
+use std::net::IpAddr;
+
+use once_cell::sync::Lazy;
+
+// always embedded, unlike the other geoip lists
+const CLOUDFLARE_RANGES: &str = include_str!("../../data/geoip/cloudflare.txt");
+
+static CLOUDFLARE_CIDRS: Lazy<Vec<Cidr>> = Lazy::new(|| {
+    CLOUDFLARE_RANGES
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| x.parse().unwrap())
+        .collect()
+});
+
+/// Workers refuse direct `connect()` calls to these addresses.
+pub fn is_cloudflare_ip(ip: &IpAddr) -> bool {
+    CLOUDFLARE_CIDRS.iter().any(|cidr| cidr.contains(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_cloudflare_ip() {
+        assert!(is_cloudflare_ip(&"104.16.132.229".parse().unwrap()));
+        assert!(is_cloudflare_ip(&"2606:4700::6810:84e5".parse().unwrap()));
+        assert!(!is_cloudflare_ip(&"8.8.8.8".parse().unwrap()));
+    }
+}
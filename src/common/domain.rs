@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+/// Maps domain patterns to values. A pattern is either an exact name
+/// (`example.com`) or a wildcard (`*.example.com`) matching every subdomain.
+#[derive(Clone, Debug, Default)]
+pub struct DomainMap<T> {
+    exact: HashMap<String, T>,
+    wildcard: HashMap<String, T>,
+}
+
+impl<T> DomainMap<T> {
+    pub fn new() -> Self {
+        Self {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, pattern: &str, value: T) {
+        let pattern = normalize(pattern);
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => self.wildcard.insert(suffix.to_string(), value),
+            None => self.exact.insert(pattern, value),
+        };
+    }
+
+    /// Finds the exact entry of `domain`, or else the wildcard entry of its
+    /// closest parent.
+    pub fn get(&self, domain: &str) -> Option<&T> {
+        let domain = normalize(domain);
+        if let Some(value) = self.exact.get(&domain) {
+            return Some(value);
+        }
+
+        let mut rest = domain.as_str();
+        while let Some((_, parent)) = rest.split_once('.') {
+            if let Some(value) = self.wildcard.get(parent) {
+                return Some(value);
+            }
+            rest = parent;
+        }
+
+        None
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty()
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_map() {
+        let mut map = DomainMap::new();
+        map.insert("example.com", 1);
+        map.insert("*.corp.example", 2);
+        map.insert("*.eu.corp.example", 3);
+        map.insert("eu.corp.example", 4);
+
+        assert_eq!(map.get("Example.com."), Some(&1));
+        assert_eq!(map.get("www.example.com"), None);
+        assert_eq!(map.get("corp.example"), None);
+        assert_eq!(map.get("git.corp.example"), Some(&2));
+        assert_eq!(map.get("a.b.corp.example"), Some(&2));
+        assert_eq!(map.get("git.eu.corp.example"), Some(&3));
+        assert_eq!(map.get("eu.corp.example"), Some(&4));
+    }
+}
@@ -40,8 +40,8 @@ impl RecursiveHash {
         let mut ipad = [0u8; 64];
         let mut opad = [0u8; 64];
 
-        ipad[..key.len()].copy_from_slice(&key);
-        opad[..key.len()].copy_from_slice(&key);
+        ipad[..key.len()].copy_from_slice(key);
+        opad[..key.len()].copy_from_slice(key);
 
         for b in ipad.iter_mut() {
             *b ^= 0x36;
@@ -68,8 +68,8 @@ impl Hasher for RecursiveHash {
     fn clone(&self) -> Box<dyn Hasher> {
         let inner = self.inner.clone();
         let outer = self.outer.clone();
-        let ipad = self.ipad.clone();
-        let opad = self.opad.clone();
+        let ipad = self.ipad;
+        let opad = self.opad;
 
         Box::new(Self {
             inner,
@@ -84,10 +84,10 @@ impl Hasher for RecursiveHash {
     }
 
     fn finalize(&mut self) -> [u8; 32] {
-        let result: [u8; 32] = self.inner.finalize().into();
+        let result: [u8; 32] = self.inner.finalize();
         self.outer.update(&self.opad);
         self.outer.update(&result);
-        self.outer.finalize().into()
+        self.outer.finalize()
     }
 }
 
@@ -97,7 +97,7 @@ pub fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
         Box::new(Sha256Hash::new()),
     ));
 
-    for p in path.into_iter() {
+    for p in path.iter() {
         current = Box::new(RecursiveHash::new(p, current));
     }
 
@@ -105,6 +105,28 @@ pub fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
     current.finalize()
 }
 
+/// HMAC-SHA256 (RFC 2104).
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let digest;
+    let key = if key.len() > 64 {
+        digest = Sha256::digest(key);
+        &digest[..]
+    } else {
+        key
+    };
+
+    let mut hash = RecursiveHash::new(key, Box::new(Sha256Hash::new()));
+    hash.update(data);
+    hash.finalize()
+}
+
+/// Compares secrets in time independent of where they differ. Both sides
+/// are hashed first, so their lengths don't show either.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,4 +144,32 @@ mod tests {
             [117, 82, 144, 159, 147, 65, 74, 253, 91, 74, 70, 84, 114, 118, 203, 30]
         );
     }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test case 2
+        let res = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            res[..8],
+            [0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e]
+        );
+
+        // test case 6, keys longer than a block are hashed first
+        let res = hmac_sha256(
+            &[0xaa; 131],
+            b"Test Using Larger Than Block-Size Key - Hash Key First",
+        );
+        assert_eq!(
+            res[..8],
+            [0x60, 0xe4, 0x31, 0x59, 0x1e, 0xe0, 0xb6, 0x7f]
+        );
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(!constant_time_eq(b"", b"token"));
+    }
 }
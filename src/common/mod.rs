@@ -1,8 +1,10 @@
+pub mod cidr;
+pub mod cloudflare;
+pub mod domain;
 pub mod hash;
 
 use std::net::{Ipv4Addr, Ipv6Addr};
 use tokio::io::{AsyncRead, AsyncReadExt};
-use worker::*;
 
 pub const KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY: &[u8] =
     b"VMess Header AEAD Key_Length";
@@ -41,7 +43,7 @@ macro_rules! sha256 {
     }
 }
 
-pub async fn parse_addr<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Result<String> {
+pub async fn parse_addr<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> std::io::Result<String> {
     // combined addr type between Vmess, VLESS, and Trojan.
     // VLESS wouldn't connect to ipv6 address due to mismatch addr type
     let addr = match buf.read_u8().await? {
@@ -59,22 +61,32 @@ pub async fn parse_addr<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Resul
         4 => {
             let mut addr = [0u8; 16];
             buf.read_exact(&mut addr).await?;
-            Ipv6Addr::new(
-                ((addr[0] as u16) << 16) | (addr[1] as u16),
-                ((addr[2] as u16) << 16) | (addr[3] as u16),
-                ((addr[4] as u16) << 16) | (addr[5] as u16),
-                ((addr[6] as u16) << 16) | (addr[7] as u16),
-                ((addr[8] as u16) << 16) | (addr[9] as u16),
-                ((addr[10] as u16) << 16) | (addr[11] as u16),
-                ((addr[12] as u16) << 16) | (addr[13] as u16),
-                ((addr[14] as u16) << 16) | (addr[15] as u16),
-            )
-            .to_string()
+            Ipv6Addr::from(addr).to_string()
         }
         _ => {
-            return Err(Error::RustError("invalid address".to_string()));
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "invalid address type",
+            ));
         }
     };
 
     Ok(addr)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_addr() {
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let mut v6: &[u8] = &[4, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x12, 0x34];
+            assert_eq!(parse_addr(&mut v6).await.unwrap(), "2001:db8::1234");
+
+            let mut domain: &[u8] = b"\x02\x0bexample.com";
+            assert_eq!(parse_addr(&mut domain).await.unwrap(), "example.com");
+        });
+    }
+}
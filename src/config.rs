@@ -1,3 +1,15 @@
+use crate::common::cidr::Cidr;
+use crate::proxy::acl::Acl;
+use crate::proxy::dns::{BlockMode, Blocklist, DnsRules, Hosts, Upstream};
+use crate::proxy::routing::Routing;
+use crate::proxy::sniff::AppProtocol;
+use crate::proxy::timeout::Timeouts;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::rc::Rc;
+use std::time::Duration;
+
 use uuid::Uuid;
 
 #[derive(Clone)]
@@ -6,4 +18,103 @@ pub struct Config {
     pub host: String,
     pub proxy_addr: String,
     pub proxy_port: u16,
+    pub nat64_prefix: Option<Ipv6Addr>,
+    pub dns_upstreams: Vec<Upstream>,
+    pub dns_timeout: Duration,
+    pub dns_rules: Rc<DnsRules>,
+    pub hosts: Rc<Hosts>,
+    pub block_mode: BlockMode,
+    pub blocklists: Vec<Rc<Blocklist>>,
+    pub routing: Rc<Routing>,
+    /// Dial the sniffed domain instead of an ip destination.
+    pub sniff_override: bool,
+    /// Protocols rejected when sniffed from a session's first payload.
+    pub blocked_protocols: Vec<AppProtocol>,
+    pub acl: Rc<Acl>,
+    pub timeouts: Timeouts,
+    /// Interval of keepalive frames to the client, zero when disabled.
+    pub keepalive: Duration,
+}
+
+/// Accepts `64:ff9b::/96` or a bare `64:ff9b::`; only /96 prefixes are supported.
+pub fn parse_nat64_prefix(s: &str) -> Option<Ipv6Addr> {
+    let s = s.trim();
+    let cidr: Cidr = if s.contains('/') {
+        s.parse().ok()?
+    } else {
+        format!("{}/96", s).parse().ok()?
+    };
+
+    match cidr.addr() {
+        IpAddr::V6(addr) if cidr.prefix() == 96 => Some(addr),
+        _ => None,
+    }
+}
+
+/// Parses a comma separated list of dns upstreams, skipping invalid entries.
+pub fn parse_dns_upstreams(s: &str) -> Vec<Upstream> {
+    s.split(',')
+        .filter(|x| !x.trim().is_empty())
+        .filter_map(|x| x.parse().map_err(|e| worker::console_error!("{}", e)).ok())
+        .collect()
+}
+
+/// Protocols blocked when BLOCK_PROTOCOLS is unset; each of them gets
+/// accounts flagged for abuse.
+pub const DEFAULT_BLOCKED_PROTOCOLS: &str = "bittorrent,smtp,quic";
+
+/// Parses a comma separated list of app protocols, skipping invalid entries.
+pub fn parse_protocols(s: &str) -> Vec<AppProtocol> {
+    parse_list(s)
+        .iter()
+        .filter_map(|x| x.parse().map_err(|e| worker::console_error!("{}", e)).ok())
+        .collect()
+}
+
+/// Parses a comma separated list of ports and ranges such as `80,443,8000-9000`,
+/// skipping invalid entries.
+pub fn parse_ports(s: &str) -> Vec<(u16, u16)> {
+    parse_list(s)
+        .iter()
+        .filter_map(|x| {
+            let (start, end) = x.split_once('-').unwrap_or((x, x));
+            match (start.trim().parse(), end.trim().parse()) {
+                (Ok(start), Ok(end)) if start <= end => Some((start, end)),
+                _ => {
+                    worker::console_error!("invalid port range: {}", x);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Parses a comma separated list of cidrs, skipping invalid entries.
+pub fn parse_cidrs(s: &str) -> Vec<Cidr> {
+    parse_list(s)
+        .iter()
+        .filter_map(|x| x.parse().map_err(|e| worker::console_error!("{}", e)).ok())
+        .collect()
+}
+
+/// Parses a comma separated list of names, e.g. `ads,trackers`.
+pub fn parse_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Parses split-horizon rules such as
+/// `{"*.corp.example": "https://10.0.0.1/dns-query"}`, where each value is a
+/// comma separated upstream list.
+pub fn parse_dns_rules(json: &str) -> Result<DnsRules, String> {
+    let entries: HashMap<String, String> =
+        serde_json::from_str(json).map_err(|e| format!("invalid dns rules: {}", e))?;
+
+    let mut rules = DnsRules::new();
+    for (pattern, upstreams) in entries {
+        rules.insert(&pattern, parse_dns_upstreams(&upstreams));
+    }
+    Ok(rules)
 }
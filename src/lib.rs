@@ -1,11 +1,30 @@
 mod common;
 mod config;
 mod proxy;
+mod status;
 
+use crate::common::hash::constant_time_eq;
 use crate::config::Config;
+use crate::proxy::acl::Acl;
+use crate::proxy::dns::blocklist;
+use crate::proxy::error::CLOSE_NORMAL;
+use crate::proxy::log::{AccessLog, LogLevel, Outcome};
+use crate::proxy::metrics;
+use crate::proxy::privacy::{Dest, Policy, Privacy};
+use crate::proxy::routing;
+use crate::proxy::timeout::{self, Timeouts};
+use crate::proxy::dns::message::{Message, MAX_MESSAGE_SIZE};
+use crate::proxy::dns::resolver::{DEFAULT_TIMEOUT, DEFAULT_UPSTREAM, DNS_MESSAGE};
+use crate::proxy::dns::Resolve;
 use crate::proxy::*;
 
-use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use std::rc::Rc;
+
+use base64::{
+    engine::general_purpose::{URL_SAFE, URL_SAFE_NO_PAD},
+    Engine as _,
+};
+use futures_util::StreamExt;
 
 use uuid::Uuid;
 use worker::*;
@@ -15,18 +34,137 @@ use regex::Regex;
 static PROXYIP_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"^.+-\d+$").unwrap());
 
 #[event(fetch)]
-async fn main(req: Request, env: Env, _: Context) -> Result<Response> {
+async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    let log_level = env
+        .var("LOG_LEVEL")
+        .ok()
+        .and_then(|x| {
+            x.to_string()
+                .parse()
+                .map_err(|e| console_error!("{}", e))
+                .ok()
+        })
+        .unwrap_or_default();
+    LogLevel::set_global(log_level);
+    let privacy = env
+        .var("PRIVACY_MODE")
+        .ok()
+        .and_then(|x| {
+            x.to_string()
+                .parse()
+                .map_err(|e| console_error!("{}", e))
+                .ok()
+        })
+        .unwrap_or_default();
+    let secret = env
+        .secret("PRIVACY_SECRET")
+        .map(|x| x.to_string())
+        .unwrap_or_default();
+    let privacy = if privacy == Privacy::Hashed && secret.is_empty() {
+        console_error!("PRIVACY_MODE=hashed needs PRIVACY_SECRET, logging no destinations");
+        Privacy::Off
+    } else {
+        privacy
+    };
+    Policy::set_global(Policy {
+        mode: privacy,
+        secret: secret.into_bytes(),
+    });
     let uuid = env
         .var("UUID")
         .map(|x| Uuid::parse_str(&x.to_string()).unwrap_or_default())?;
     let host = req.url()?.host().map(|x| x.to_string()).unwrap_or_default();
+    let nat64_prefix = env
+        .var("NAT64_PREFIX")
+        .ok()
+        .and_then(|x| config::parse_nat64_prefix(&x.to_string()));
+    let dns_upstreams = env
+        .var("DNS_UPSTREAMS")
+        .map(|x| config::parse_dns_upstreams(&x.to_string()))
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| vec![DEFAULT_UPSTREAM.parse().unwrap()]);
+    let dns_timeout = env
+        .var("DNS_TIMEOUT_MS")
+        .ok()
+        .and_then(|x| x.to_string().parse().ok())
+        .map(std::time::Duration::from_millis)
+        .unwrap_or(DEFAULT_TIMEOUT);
+    let dns_rules = env
+        .var("DNS_RULES")
+        .ok()
+        .and_then(|x| {
+            config::parse_dns_rules(&x.to_string())
+                .map_err(|e| console_error!("{}", e))
+                .ok()
+        })
+        .unwrap_or_default();
+    let hosts = env
+        .var("DNS_HOSTS")
+        .ok()
+        .and_then(|x| {
+            dns::hosts::parse_hosts(&x.to_string())
+                .map_err(|e| console_error!("{}", e))
+                .ok()
+        })
+        .unwrap_or_default();
+    let block_mode = env
+        .var("BLOCK_MODE")
+        .ok()
+        .and_then(|x| x.to_string().parse().ok())
+        .unwrap_or_default();
+    let sniff_override = env
+        .var("SNIFF_OVERRIDE")
+        .map(|x| matches!(x.to_string().trim(), "true" | "1"))
+        .unwrap_or(false);
+    let blocked_protocols = env
+        .var("BLOCK_PROTOCOLS")
+        .map(|x| x.to_string())
+        .unwrap_or_else(|_| config::DEFAULT_BLOCKED_PROTOCOLS.to_string());
+    let blocked_protocols = config::parse_protocols(&blocked_protocols);
+    let acl_var = |name: &str| env.var(name).map(|x| x.to_string()).unwrap_or_default();
+    let acl = Acl {
+        allow_ports: config::parse_ports(&acl_var("ALLOW_PORTS")),
+        deny_ports: config::parse_ports(&acl_var("DENY_PORTS")),
+        allow_cidrs: config::parse_cidrs(&acl_var("ALLOW_CIDRS")),
+        deny_cidrs: config::parse_cidrs(&acl_var("DENY_CIDRS")),
+    };
+    let duration_ms = |name: &str, default| {
+        env.var(name)
+            .ok()
+            .and_then(|x| x.to_string().parse().ok())
+            .map(std::time::Duration::from_millis)
+            .unwrap_or(default)
+    };
+    let timeouts = Timeouts {
+        handshake: duration_ms("HANDSHAKE_TIMEOUT_MS", timeout::DEFAULT_HANDSHAKE_TIMEOUT),
+        connect: duration_ms("CONNECT_TIMEOUT_MS", timeout::DEFAULT_CONNECT_TIMEOUT),
+        idle: duration_ms("IDLE_TIMEOUT_MS", timeout::DEFAULT_IDLE_TIMEOUT),
+        lifetime: duration_ms("MAX_LIFETIME_MS", timeout::DEFAULT_MAX_LIFETIME),
+    };
     let config = Config {
         uuid,
         host: host.clone(),
         proxy_addr: host.clone(),
         proxy_port: 80,
+        nat64_prefix,
+        dns_upstreams,
+        dns_timeout,
+        dns_rules: Rc::new(dns_rules),
+        hosts: Rc::new(hosts),
+        block_mode,
+        blocklists: Vec::new(),
+        routing: Rc::default(),
+        sniff_override,
+        blocked_protocols,
+        acl: Rc::new(acl),
+        timeouts,
+        keepalive: duration_ms("KEEPALIVE_INTERVAL_MS", std::time::Duration::ZERO),
     };
 
+    // the batch is sent after the response, if it is due
+    ctx.wait_until(metrics::flush_if_due(env.clone()));
+
     Router::with_data(config)
         .on("/link", link)
         .on_async("/link/:proxy", link_with_proxy)
@@ -36,6 +174,11 @@ async fn main(req: Request, env: Env, _: Context) -> Result<Response> {
             new_url.set_path("/link");
             Response::redirect(new_url)
         })
+        .on("/healthz", |_, _| Response::ok("OK"))
+        .on_async("/status", status)
+        .on_async("/metrics", metrics)
+        .on_async("/dns-query", dns_query)
+        .on_async("/dns-query/:token", dns_query)
         .on_async("/:proxyip", tunnel)
         .run(req, env)
         .await
@@ -55,14 +198,44 @@ async fn tunnel(req: Request, mut cx: RouteContext<Config>) -> Result<Response>
 
     let upgrade = req.headers().get("Upgrade")?.unwrap_or_default();
     if upgrade == "websocket" {
+        let names = requested_blocklists(&req, &cx.env)?;
+        cx.data.blocklists = blocklist::load(&cx.env, &names).await;
+        cx.data.routing = routing::load(&cx.env).await;
+
         let WebSocketPair { server, client } = WebSocketPair::new()?;
         server.accept()?;
 
+        let client_ip = req.headers().get("CF-Connecting-IP")?.unwrap_or_default();
+        let (colo, country) = req
+            .cf()
+            .map(|cf| (cf.colo(), cf.country().unwrap_or_default()))
+            .unwrap_or_default();
+
         wasm_bindgen_futures::spawn_local(async move {
-            let events = server.events().unwrap();
-            if let Err(e) = ProxyStream::new(cx.data, &server, events).process().await {
-                console_log!("[tunnel]: {}", e);
-            }
+            let events = match server.events() {
+                Ok(events) => events,
+                Err(e) => return console_error!("[tunnel] websocket events: {}", e),
+            };
+            let mut stream = ProxyStream::new(cx.data, &server, events);
+            stream.session.client_ip = client_ip;
+            stream.session.colo = colo;
+            stream.session.country = country;
+
+            let (level, close_code, reason, error) = match stream.process().await {
+                Ok(()) => (LogLevel::Info, CLOSE_NORMAL, "session ended".to_string(), None),
+                Err(e) => (e.log_level(), e.close_code(), e.to_string(), Some(e.label())),
+            };
+            stream.close(close_code, &reason);
+
+            let outcome = Outcome {
+                level,
+                close_code,
+                reason: &reason,
+                error,
+            };
+            AccessLog::new(&stream.session, &outcome, Date::now().as_millis()).emit();
+            metrics::record_session(&stream.session, error);
+            metrics::flush_if_due(cx.env).await;
         });
 
         Response::from_websocket(client)
@@ -72,6 +245,159 @@ async fn tunnel(req: Request, mut cx: RouteContext<Config>) -> Result<Response>
     }
 }
 
+/// Dns blocklists are opt-in, per path with `?block=ads,trackers`, falling
+/// back to the `BLOCKLISTS` variable.
+fn requested_blocklists(req: &Request, env: &Env) -> Result<Vec<String>> {
+    let names = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "block")
+        .map(|(_, v)| config::parse_list(&v))
+        .or_else(|| {
+            env.var("BLOCKLISTS")
+                .ok()
+                .map(|x| config::parse_list(&x.to_string()))
+        })
+        .unwrap_or_default();
+    Ok(names)
+}
+
+/// Checks the bearer token of an operator endpoint, returning the error
+/// response to send instead. Endpoints are disabled while their token
+/// secret is unset.
+fn check_token(req: &Request, env: &Env, secret: &str) -> Result<Option<Response>> {
+    let token = match env.secret(secret) {
+        Ok(token) => token.to_string(),
+        Err(_) => return Response::error("Not Found", 404).map(Some),
+    };
+    let auth = req.headers().get("Authorization")?.unwrap_or_default();
+    let sent = auth.strip_prefix("Bearer ").unwrap_or_default();
+    if token.is_empty() || !constant_time_eq(sent.as_bytes(), token.as_bytes()) {
+        return Response::error("Unauthorized", 401).map(Some);
+    }
+    Ok(None)
+}
+
+/// Prometheus scrape endpoint, protected by `METRICS_TOKEN`.
+async fn metrics(req: Request, cx: RouteContext<Config>) -> Result<Response> {
+    if let Some(response) = check_token(&req, &cx.env, "METRICS_TOKEN")? {
+        return Ok(response);
+    }
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "text/plain; version=0.0.4")?;
+    Ok(Response::ok(metrics::scrape(&cx.env).await?)?.with_headers(headers))
+}
+
+/// Json report for monitoring, protected by `STATUS_TOKEN`.
+async fn status(req: Request, cx: RouteContext<Config>) -> Result<Response> {
+    if let Some(response) = check_token(&req, &cx.env, "STATUS_TOKEN")? {
+        return Ok(response);
+    }
+
+    let routing = routing::load(&cx.env).await;
+    let report = status::report(&cx.data, &routing).await;
+    let mut response = Response::from_json(&report)?;
+    response.headers_mut().set("Cache-Control", "no-store")?;
+    Ok(response)
+}
+
+// https://datatracker.ietf.org/doc/html/rfc8484
+async fn dns_query(mut req: Request, mut cx: RouteContext<Config>) -> Result<Response> {
+    let tokens = cx
+        .env
+        .var("DOH_TOKENS")
+        .map(|x| config::parse_list(&x.to_string()))
+        .unwrap_or_default();
+    if tokens.is_empty() {
+        // an open resolver has to be asked for explicitly
+        let public = cx
+            .env
+            .var("DOH_PUBLIC")
+            .is_ok_and(|x| matches!(x.to_string().trim(), "true" | "1"));
+        if !public {
+            return Response::error("Not Found", 404);
+        }
+    } else if !cx.param("token").is_some_and(|x| {
+        tokens
+            .iter()
+            .any(|t| constant_time_eq(t.as_bytes(), x.as_bytes()))
+    }) {
+        return Response::error("Unauthorized", 401);
+    }
+
+    let query = match req.method() {
+        Method::Get => {
+            let url = req.url()?;
+            let dns = url.query_pairs().find(|(k, _)| k == "dns");
+            match dns.and_then(|(_, v)| URL_SAFE_NO_PAD.decode(v.trim_end_matches('=')).ok()) {
+                Some(query) => query,
+                None => return Response::error("Bad Request", 400),
+            }
+        }
+        Method::Post => {
+            let content_type = req.headers().get("Content-Type")?.unwrap_or_default();
+            let media_type = content_type.split(';').next().unwrap_or_default().trim();
+            if !media_type.eq_ignore_ascii_case(DNS_MESSAGE) {
+                return Response::error("Unsupported Media Type", 415);
+            }
+            match read_body(&mut req, MAX_MESSAGE_SIZE).await? {
+                Some(query) => query,
+                None => return Response::error("Payload Too Large", 413),
+            }
+        }
+        _ => return Response::error("Method Not Allowed", 405),
+    };
+    let msg = match Message::parse(&query) {
+        Ok(msg) => msg,
+        Err(_) => return Response::error("Bad Request", 400),
+    };
+
+    let names = requested_blocklists(&req, &cx.env)?;
+    cx.data.blocklists = blocklist::load(&cx.env, &names).await;
+    metrics::inc(metrics::DNS_QUERIES, &[("source", "doh")]);
+    let response = match dns::session_resolver(&cx.data).exchange(&query).await {
+        Ok(response) => response,
+        Err(e) => {
+            let name = msg.questions.first().map(|x| x.name.as_str()).unwrap_or_default();
+            console_error!("error resolving {}: {}", Dest::host(name), e);
+            return Response::error("Bad Gateway", 502);
+        }
+    };
+
+    let max_age = Message::parse(&response)
+        .ok()
+        .and_then(|x| x.min_ttl())
+        .unwrap_or_default();
+    let mut headers = Headers::new();
+    headers.set("Content-Type", DNS_MESSAGE)?;
+    headers.set("Cache-Control", &format!("max-age={}", max_age))?;
+    Ok(Response::from_bytes(response)?.with_headers(headers))
+}
+
+/// Reads the request body, or `None` if it is longer than `max` bytes. The
+/// declared length is checked first and the stream is cut off past `max`, so
+/// an oversized body is never buffered.
+async fn read_body(req: &mut Request, max: usize) -> Result<Option<Vec<u8>>> {
+    let declared = req
+        .headers()
+        .get("Content-Length")?
+        .and_then(|x| x.parse::<usize>().ok());
+    if declared.is_some_and(|x| x > max) {
+        return Ok(None);
+    }
+
+    let mut body = Vec::new();
+    let mut stream = req.stream()?;
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > max {
+            return Ok(None);
+        }
+    }
+    Ok(Some(body))
+}
+
 fn link(_: Request, cx: RouteContext<Config>) -> Result<Response> {
     generate_link_page(cx.data.clone(), None)
 }
@@ -147,7 +473,7 @@ fn generate_link_page(config: Config, path: Option<String>) -> Result<Response>
         )
     };
 
-    let cards = vec![
+    let cards = [
         ("VMESS TLS", vmess(true), "vmess_tls"),
         ("VMESS NTLS", vmess(false), "vmess_ntls"),
         ("VLESS TLS", vless(true), "vless_tls"),
@@ -0,0 +1,184 @@
+use super::privacy::Dest;
+use crate::common::cidr::Cidr;
+
+use std::fmt;
+use std::net::IpAddr;
+
+use once_cell::sync::Lazy;
+
+// always embedded, unlike the other geoip lists
+const PRIVATE_RANGES: &str = include_str!("../../data/geoip/private.txt");
+
+/// Loopback, private, link-local (cloud metadata), multicast and other
+/// special purpose ranges that are never a valid tunnel destination.
+static PRIVATE_CIDRS: Lazy<Vec<Cidr>> = Lazy::new(|| {
+    PRIVATE_RANGES
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| x.parse().unwrap())
+        .collect()
+});
+
+/// Why a destination was rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AclError {
+    Port(u16),
+    Address(IpAddr),
+    SelfLoop(String),
+    /// A domain without addresses, which can't be checked.
+    Unresolved(String),
+}
+
+impl fmt::Display for AclError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Port(port) => write!(f, "destination port {} is not allowed", port),
+            Self::Address(ip) => write!(
+                f,
+                "destination address {} is not allowed",
+                Dest::host(&ip.to_string())
+            ),
+            Self::SelfLoop(host) => {
+                write!(f, "destination {} is the worker itself", Dest::host(host))
+            }
+            Self::Unresolved(host) => {
+                write!(f, "destination {} does not resolve", Dest::host(host))
+            }
+        }
+    }
+}
+
+impl std::error::Error for AclError {}
+
+/// Destination access list. Private ranges and the worker's own host are
+/// always denied unless `allow_cidrs` makes an exception; when `allow_ports`
+/// is set only those ports may be dialed. Targets that resolve to nothing are
+/// denied, so the addresses that passed are the only ones ever dialed.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    pub allow_ports: Vec<(u16, u16)>,
+    pub deny_ports: Vec<(u16, u16)>,
+    pub allow_cidrs: Vec<Cidr>,
+    pub deny_cidrs: Vec<Cidr>,
+}
+
+impl Acl {
+    /// Checks a target given by the client, along with the addresses it
+    /// resolves to, against the list. `host` is the worker's own hostname,
+    /// matched against the target and the domain sniffed from its first
+    /// payload, since an address only reaches the worker when the TLS SNI or
+    /// HTTP Host names it.
+    pub fn check(
+        &self,
+        host: &str,
+        addr: &str,
+        sniffed: Option<&str>,
+        ips: &[IpAddr],
+        port: u16,
+    ) -> Result<(), AclError> {
+        let in_ports = |ports: &[(u16, u16)]| {
+            ports
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&port))
+        };
+        if in_ports(&self.deny_ports)
+            || (!self.allow_ports.is_empty() && !in_ports(&self.allow_ports))
+        {
+            return Err(AclError::Port(port));
+        }
+
+        let addr = addr.trim_end_matches('.');
+        for name in [Some(addr), sniffed].into_iter().flatten() {
+            if name.trim_end_matches('.').eq_ignore_ascii_case(host) {
+                return Err(AclError::SelfLoop(name.to_string()));
+            }
+        }
+        if ips.is_empty() {
+            return Err(AclError::Unresolved(addr.to_string()));
+        }
+
+        for ip in ips {
+            let ip = to_canonical(ip);
+            if self.allow_cidrs.iter().any(|x| x.contains(&ip)) {
+                continue;
+            }
+            if self.deny_cidrs.iter().any(|x| x.contains(&ip))
+                || PRIVATE_CIDRS.iter().any(|x| x.contains(&ip))
+            {
+                return Err(AclError::Address(ip));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Unwraps ipv4-mapped ipv6 addresses, which would otherwise slip past the
+/// ipv4 ranges.
+fn to_canonical(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*ip, IpAddr::V4),
+        _ => *ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+        let acl = Acl::default();
+        let host = "siren.example.workers.dev";
+
+        assert_eq!(
+            acl.check(host, "example.com", None, &[ip("93.184.215.14")], 443),
+            Ok(())
+        );
+        assert_eq!(
+            acl.check(host, "127.0.0.1", None, &[ip("127.0.0.1")], 80),
+            Err(AclError::Address(ip("127.0.0.1")))
+        );
+        assert_eq!(
+            acl.check(host, "metadata", None, &[ip("169.254.169.254")], 80),
+            Err(AclError::Address(ip("169.254.169.254")))
+        );
+        assert_eq!(
+            acl.check(host, "::ffff:10.0.0.1", None, &[ip("::ffff:10.0.0.1")], 80),
+            Err(AclError::Address(ip("10.0.0.1")))
+        );
+        assert_eq!(
+            acl.check(host, "Siren.example.workers.dev.", None, &[], 443),
+            Err(AclError::SelfLoop("Siren.example.workers.dev".to_string()))
+        );
+        assert_eq!(
+            acl.check(host, "104.21.0.1", Some(host), &[ip("104.21.0.1")], 443),
+            Err(AclError::SelfLoop(host.to_string()))
+        );
+        assert_eq!(
+            acl.check(host, "nx.example", None, &[], 443),
+            Err(AclError::Unresolved("nx.example".to_string()))
+        );
+
+        let acl = Acl {
+            allow_ports: vec![(80, 80), (443, 443), (8000, 9000)],
+            deny_ports: vec![(8080, 8080)],
+            allow_cidrs: vec!["10.1.0.0/16".parse().unwrap()],
+            deny_cidrs: vec!["93.184.215.0/24".parse().unwrap()],
+        };
+        assert_eq!(acl.check(host, "10.1.2.3", None, &[ip("10.1.2.3")], 8443), Ok(()));
+        assert_eq!(
+            acl.check(host, "10.1.2.3", None, &[ip("10.1.2.3")], 22),
+            Err(AclError::Port(22))
+        );
+        assert_eq!(
+            acl.check(host, "10.1.2.3", None, &[ip("10.1.2.3")], 8080),
+            Err(AclError::Port(8080))
+        );
+        assert_eq!(
+            acl.check(host, "example.com", None, &[ip("93.184.215.14")], 443),
+            Err(AclError::Address(ip("93.184.215.14")))
+        );
+    }
+}
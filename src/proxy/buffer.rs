@@ -0,0 +1,210 @@
+//! Buffers between the websocket and the stream interface of `ProxyStream`.
+//! Kept free of worker types so they can be exercised natively.
+
+use std::collections::VecDeque;
+use std::io;
+use std::task::{Context, Poll, Waker};
+
+use bytes::{Bytes, BytesMut};
+
+/// Queue of websocket frames received from the client. Frames are kept as
+/// they arrive and handed out as `Bytes` slices, so reads don't copy them
+/// into an intermediate buffer.
+#[derive(Debug)]
+pub struct ReadBuffer {
+    frames: VecDeque<Bytes>,
+    len: usize,
+    max: usize,
+}
+
+impl ReadBuffer {
+    pub fn new(max: usize) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            len: 0,
+            max,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queues a frame, failing when it would take the buffer past its limit.
+    pub fn push(&mut self, frame: Bytes) -> io::Result<()> {
+        if self.len + frame.len() > self.max {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!("more than {} bytes of client data queued", self.max),
+            ));
+        }
+        if !frame.is_empty() {
+            self.len += frame.len();
+            self.frames.push_back(frame);
+        }
+        Ok(())
+    }
+
+    /// Returns up to `n` bytes from the front of the buffer. Only copies when
+    /// the bytes span frames, which is rare outside of protocol headers.
+    pub fn peek(&mut self, n: usize) -> &[u8] {
+        let n = n.min(self.len);
+        if self.frames.front().is_some_and(|x| x.len() < n) {
+            let mut merged = BytesMut::with_capacity(n);
+            while merged.len() < n {
+                let frame = self.frames.pop_front().unwrap();
+                merged.extend_from_slice(&frame);
+            }
+            self.frames.push_front(merged.freeze());
+        }
+        self.frames.front().map_or(&[], |x| &x[..n])
+    }
+
+    /// Takes up to `n` bytes of the front frame without copying.
+    pub fn take(&mut self, n: usize) -> Option<Bytes> {
+        let front = self.frames.front_mut()?;
+        let chunk = if front.len() <= n {
+            self.frames.pop_front()?
+        } else {
+            front.split_to(n)
+        };
+        self.len -= chunk.len();
+        Some(chunk)
+    }
+
+    /// Takes everything buffered, copying only when there are several frames.
+    pub fn take_all(&mut self) -> Bytes {
+        self.peek(self.len);
+        self.take(self.len).unwrap_or_default()
+    }
+}
+
+/// Coalesces small writes into websocket frames of at most `max_frame`
+/// bytes. It never holds more than one frame, so a full buffer has to be
+/// sent before more data is accepted. Frames handed out count against
+/// `max_unsent` until the caller reports them drained, and `poll_ready` waits
+/// while that budget is used up.
+#[derive(Debug)]
+pub struct WriteBuffer {
+    buf: BytesMut,
+    max_frame: usize,
+    unsent: usize,
+    max_unsent: usize,
+    waker: Option<Waker>,
+}
+
+impl WriteBuffer {
+    pub fn new(max_frame: usize, max_unsent: usize) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(max_frame),
+            max_frame,
+            unsent: 0,
+            max_unsent,
+            waker: None,
+        }
+    }
+
+    /// Copies as much of `data` as fits in the current frame, returning the
+    /// number of bytes taken.
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(self.max_frame - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        n
+    }
+
+    /// Takes the frame once it is full.
+    pub fn full_frame(&mut self) -> Option<Bytes> {
+        (self.buf.len() >= self.max_frame).then(|| self.take())
+    }
+
+    /// Takes whatever is buffered, for flushes.
+    pub fn frame(&mut self) -> Option<Bytes> {
+        (!self.buf.is_empty()).then(|| self.take())
+    }
+
+    fn take(&mut self) -> Bytes {
+        self.unsent += self.buf.len();
+        self.buf.split().freeze()
+    }
+
+    /// Ready while the frames handed out are within the budget, otherwise
+    /// pending until `drained` is called.
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.unsent < self.max_unsent {
+            return Poll::Ready(());
+        }
+        self.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Marks every frame handed out as sent, waking a pending `poll_ready`.
+    pub fn drained(&mut self) {
+        self.unsent = 0;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_buffer() {
+        let mut buf = ReadBuffer::new(16);
+        buf.push(Bytes::from_static(b"abc")).unwrap();
+        buf.push(Bytes::new()).unwrap();
+        buf.push(Bytes::from_static(b"defgh")).unwrap();
+        assert_eq!(buf.len(), 8);
+        assert_eq!(buf.peek(2), b"ab");
+        assert_eq!(buf.peek(5), b"abcde");
+        assert_eq!(buf.peek(100), b"abcdefgh");
+
+        assert_eq!(buf.take(3).as_deref(), Some(&b"abc"[..]));
+        assert!(buf.push(Bytes::from_static(&[0; 12])).is_err());
+        buf.push(Bytes::from_static(b"ij")).unwrap();
+        assert_eq!(buf.take_all(), &b"defghij"[..]);
+        assert!(buf.is_empty());
+        assert_eq!(buf.take(1), None);
+    }
+
+    #[test]
+    fn test_write_buffer() {
+        let mut buf = WriteBuffer::new(8, 64);
+        assert_eq!(buf.push(b"abc"), 3);
+        assert_eq!(buf.push(b"def"), 3);
+        assert_eq!(buf.full_frame(), None);
+        assert_eq!(buf.push(b"ghijk"), 2);
+        assert_eq!(buf.full_frame().as_deref(), Some(&b"abcdefgh"[..]));
+        assert_eq!(buf.push(b"ijk"), 3);
+        assert_eq!(buf.frame().as_deref(), Some(&b"ijk"[..]));
+        assert_eq!(buf.frame(), None);
+    }
+
+    #[test]
+    fn test_write_buffer_budget() {
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut buf = WriteBuffer::new(8, 16);
+        assert!(buf.poll_ready(&mut cx).is_ready());
+        buf.push(&[0; 8]);
+        buf.full_frame().unwrap();
+        buf.push(&[0; 4]);
+        buf.frame().unwrap();
+        assert!(buf.poll_ready(&mut cx).is_ready());
+
+        buf.push(&[0; 8]);
+        buf.full_frame().unwrap();
+        assert!(buf.poll_ready(&mut cx).is_pending());
+        assert!(buf.waker.is_some());
+
+        buf.drained();
+        assert!(buf.waker.is_none());
+        assert!(buf.poll_ready(&mut cx).is_ready());
+    }
+}
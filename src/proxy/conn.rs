@@ -1,19 +1,86 @@
+use super::buffer::{ReadBuffer, WriteBuffer};
+use super::dns::{session_resolver, Resolve, SessionResolver};
+use super::error::{ClientError, ProxyError, Result, CLOSE_NORMAL};
+use super::keepalive::Keepalive;
+use super::metrics;
+use super::outbound::{connect_any, resolve_target, tcp_candidates, Candidate};
+use super::privacy::Dest;
+use super::routing::{Action, Target};
+use super::session::{Network, Protocol, Session};
+use super::sniff::{classify, is_smtp_banner, sniff, AppProtocol};
+use super::timeout::Deadlines;
 use crate::config::Config;
 
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
 
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, Bytes};
+use futures_util::future::{poll_fn, select, Either};
 use futures_util::Stream;
 use pin_project_lite::pin_project;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use worker::*;
 
+const DNS_PORT: u16 = 53;
+/// Header bytes needed to tell the protocols apart.
+const HEADER_PEEK: usize = 62;
+/// How long to wait for the client to send data after the protocol header.
+const FIRST_PAYLOAD_WAIT: Duration = Duration::from_millis(200);
+/// Upper bound on the first response read used to probe an outbound.
+const FIRST_READ_SIZE: usize = 16 * 1024;
+/// How long a probed outbound may take to answer before the next candidate
+/// is tried.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for the greeting of a server-first protocol when the
+/// client sent nothing.
+const SERVER_FIRST_WAIT: Duration = Duration::from_secs(1);
+/// Client data kept for replaying to a fallback candidate.
+const MAX_REPLAY: usize = 64 * 1024;
+
+/// Limit on client data queued ahead of the remote. The runtime gives no way
+/// to slow the client down, so a session this far behind is closed instead
+/// of growing until the isolate runs out of memory.
+const MAX_READ_BUFFER: usize = 4 * 1024 * 1024;
+/// Remote reads while relaying.
+const RELAY_READ_SIZE: usize = 16 * 1024;
+/// Small writes are coalesced into frames of up to this size.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+/// Frames sent to the client between yields to the event loop. workerd only
+/// writes queued messages out once the isolate yields and reports nothing
+/// back, so writes wait for a yield past this budget instead of queueing
+/// whatever the remote sends in one go.
+const MAX_UNSENT: usize = 1024 * 1024;
+
+/// Close reasons must fit a control frame along with the code.
+const MAX_CLOSE_REASON: usize = 123;
+
+/// Whatever came first while probing an outbound.
+enum ProbeEvent {
+    Response(std::io::Result<usize>),
+    Client(std::io::Result<()>),
+    Timeout,
+}
+
 pin_project! {
     pub struct ProxyStream<'a> {
         pub config: Config,
+        pub resolver: SessionResolver,
+        pub session: Session,
         pub ws: &'a WebSocket,
-        pub buffer: BytesMut,
+        pub buffer: ReadBuffer,
+        pub write_buffer: WriteBuffer,
+        deadlines: Deadlines,
+        keepalive: Option<Keepalive>,
+        // yield that releases the send budget, while writes are waiting
+        drain: Option<Delay>,
+        // set once the websocket close frame is sent
+        pub closed: bool,
+        // set once the client sent its close frame
+        pub peer_closed: bool,
         #[pin]
         pub events: EventStream<'a>,
     }
@@ -21,136 +88,627 @@ pin_project! {
 
 impl<'a> ProxyStream<'a> {
     pub fn new(config: Config, ws: &'a WebSocket, events: EventStream<'a>) -> Self {
-        let buffer = BytesMut::new();
+        let buffer = ReadBuffer::new(MAX_READ_BUFFER);
+        let deadlines = Deadlines::new(&config.timeouts);
+        let keepalive = Keepalive::new(config.keepalive);
+        let resolver = session_resolver(&config);
+        let session = Session {
+            id: Session::new_id(),
+            user: config.uuid.to_string(),
+            started_ms: Date::now().as_millis(),
+            ..Default::default()
+        };
 
         Self {
             config,
+            resolver,
+            session,
             ws,
             buffer,
+            write_buffer: WriteBuffer::new(MAX_FRAME_SIZE, MAX_UNSENT),
+            deadlines,
+            keepalive,
+            drain: None,
+            closed: false,
+            peer_closed: false,
             events,
         }
     }
-    
-    pub async fn fill_buffer_until(&mut self, n: usize) -> std::io::Result<()> {
-        use futures_util::StreamExt;
 
-        while self.buffer.len() < n {
-            match self.events.next().await {
-                Some(Ok(WebsocketEvent::Message(msg))) => {
-                    if let Some(data) = msg.bytes() {
-                        self.buffer.put_slice(&data);
-                    }
-                }
-                Some(Ok(WebsocketEvent::Close(_))) => {
-                    break;
-                }
-                Some(Err(e)) => {
-                    return Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
+    /// Sends the websocket close frame after any buffered data, once; later
+    /// calls are no-ops.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if let Some(frame) = self.write_buffer.frame() {
+            let _ = send_frame(self.ws, &frame);
+        }
+        close_ws(self.ws, &mut self.closed, code, reason)
+    }
+    
+    /// Queues the next websocket message, returning false once the client
+    /// is gone. Session deadlines are checked here, since every read from the
+    /// client goes through it.
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        let mut this = self.project();
+        if let Poll::Ready(timeout) = this.deadlines.poll_expired(cx) {
+            return Poll::Ready(Err(timeout.into()));
+        }
+        if let Some(keepalive) = this.keepalive {
+            while keepalive.poll_tick(cx).is_ready() {
+                if !*this.closed {
+                    send_frame(this.ws, &[])?;
                 }
-                None => {
-                    break;
+            }
+        }
+
+        match this.events.as_mut().poll_next(cx) {
+            Poll::Ready(Some(Ok(WebsocketEvent::Message(msg)))) => {
+                this.deadlines.touch();
+                if let Some(data) = msg.bytes() {
+                    this.session.bytes_up += data.len() as u64;
+                    this.buffer.push(data.into())?;
                 }
+                Poll::Ready(Ok(true))
+            }
+            Poll::Ready(Some(Ok(WebsocketEvent::Close(_)))) => {
+                *this.peer_closed = true;
+                Poll::Ready(Ok(false))
+            }
+            Poll::Ready(Some(Err(e))) => {
+                Poll::Ready(Err(ClientError::new(ErrorKind::Other, e.to_string()).into()))
+            }
+            Poll::Ready(None) => Poll::Ready(Ok(false)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    pub async fn fill_buffer_until(&mut self, n: usize) -> std::io::Result<()> {
+        while self.buffer.len() < n {
+            if !poll_fn(|cx| Pin::new(&mut *self).poll_frame(cx)).await? {
+                break;
             }
         }
 
         Ok(())
     }
 
-    pub fn peek_buffer(&self, n: usize) -> &[u8] {
-        let len = self.buffer.len().min(n);
-        &self.buffer[..len]
+    pub fn peek_buffer(&mut self, n: usize) -> &[u8] {
+        self.buffer.peek(n)
     }
 
+    /// Reads whatever the client sent next, without copying it.
+    pub async fn read_chunk(&mut self) -> std::io::Result<Bytes> {
+        if self.buffer.is_empty() {
+            self.fill_buffer_until(1).await?;
+        }
+        Ok(self.buffer.take(usize::MAX).unwrap_or_default())
+    }
+
+    /// Detects the protocol from the start of the header: vless starts with
+    /// version 0, shadowsocks with an address type, trojan has a crlf after
+    /// its 56 bytes password hash, and anything else is taken for vmess.
     pub async fn process(&mut self) -> Result<()> {
-        self.fill_buffer_until(62).await?;
-        let peeked_buffer = self.peek_buffer(62);
-
-        if peeked_buffer[0] == 0 {
-            self.process_vless().await
-        } else if peeked_buffer[0] == 1 || peeked_buffer[0] == 3 {
-            self.process_shadowsocks().await
-        } else if peeked_buffer[56] == 13 && peeked_buffer[57] == 10 {
-            self.process_trojan().await
+        self.fill_buffer_until(HEADER_PEEK).await?;
+
+        let header = self.peek_buffer(HEADER_PEEK);
+        let protocol = match header {
+            [] => return Err(ProxyError::ClientGone("closed before the header".to_string())),
+            [0, ..] => Protocol::Vless,
+            [1 | 3, ..] => Protocol::Shadowsocks,
+            _ if header.len() < HEADER_PEEK => {
+                return Err(ProxyError::Protocol("header too short".to_string()))
+            }
+            _ if header[56..58] == *b"\r\n" => Protocol::Trojan,
+            _ => Protocol::Vmess,
+        };
+
+        match protocol {
+            Protocol::Vless => self.process_vless().await,
+            Protocol::Shadowsocks => self.process_shadowsocks().await,
+            Protocol::Trojan => self.process_trojan().await,
+            _ => self.process_vmess().await,
+        }
+    }
+
+    pub async fn handle_outbound(
+        &mut self,
+        remote_addr: String,
+        remote_port: u16,
+        is_tcp: bool,
+    ) -> Result<()> {
+        self.deadlines.handshake_done();
+        let network = if is_tcp { Network::Tcp } else { Network::Udp };
+        self.session.network = Some(network);
+        self.session.target = Some((remote_addr.clone(), remote_port));
+
+        // tcp payloads are buffered before routing so the destination domain
+        // can be sniffed from them
+        let mut payload = Bytes::new();
+        let mut sniffed = None;
+        if is_tcp && remote_port != DNS_PORT {
+            payload = self.first_payload().await?;
+            self.check_protocol(classify(&payload, remote_port, network))?;
+            sniffed = sniff(&payload);
+            if let Some(sniffed) = &sniffed {
+                self.session.sniffed = Some(sniffed.domain.clone());
+                crate::log_debug!(
+                    "sniffed {} ({}) for {}",
+                    Dest::host(&sniffed.domain),
+                    sniffed.protocol,
+                    Dest::new(&remote_addr, remote_port)
+                );
+            }
+        }
+
+        let domain = sniffed.as_ref().map(|x| x.domain.as_str());
+        let action = self.route(&remote_addr, domain, remote_port, network).await;
+        if let Some(action) = &action {
+            crate::log_debug!(
+                "routing {} [{}] to {}",
+                Dest::new(&remote_addr, remote_port),
+                network,
+                action
+            );
+        }
+
+        // only ip destinations are overridden, a domain from the client is
+        // at least as good as the sniffed one
+        let remote_addr = match sniffed {
+            Some(sniffed)
+                if self.config.sniff_override && remote_addr.parse::<IpAddr>().is_ok() =>
+            {
+                sniffed.domain
+            }
+            _ => remote_addr,
+        };
+
+        if action == Some(Action::Block) {
+            Err(ProxyError::Blocked(format!(
+                "routing rule for {}",
+                Dest::new(&remote_addr, remote_port)
+            )))
+        } else if is_tcp && remote_port == DNS_PORT {
+            self.session.outbound = Some("dns".to_string());
+            self.handle_dns_stream().await
+        } else if is_tcp {
+            let ips = self.check_destination(&remote_addr, remote_port).await?;
+            let addr_pool = tcp_candidates(
+                &self.config,
+                action.as_ref(),
+                remote_addr,
+                &ips,
+                remote_port,
+            );
+
+            self.handle_tcp_outbound(addr_pool, payload).await
+        } else {
+            self.session.outbound = Some("dns".to_string());
+            self.handle_udp_outbound(remote_port).await
+        }
+    }
+
+    /// Checks a sniffed protocol against the protocol policy.
+    fn check_protocol(&self, protocol: Option<AppProtocol>) -> Result<()> {
+        match protocol {
+            Some(protocol) if self.config.blocked_protocols.contains(&protocol) => {
+                Err(ProxyError::Blocked(format!("{} traffic", protocol)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs a tcp destination through the acl, resolving domains so they
+    /// can't be used to reach denied addresses. Returns the addresses that
+    /// passed, which are the ones dialed, so a second lookup can't rebind
+    /// the domain to a denied address.
+    async fn check_destination(&self, addr: &str, port: u16) -> Result<Vec<IpAddr>> {
+        let ips = resolve_target(&self.resolver, addr).await;
+        let sniffed = self.session.sniffed.as_deref();
+        self.config
+            .acl
+            .check(&self.config.host, addr, sniffed, &ips, port)?;
+        Ok(ips)
+    }
+
+    /// Evaluates the routing rules for a target. Domain rules see the sniffed
+    /// domain when there is one, and the target is only resolved when some
+    /// rule matches on addresses.
+    async fn route(
+        &self,
+        addr: &str,
+        sniffed: Option<&str>,
+        port: u16,
+        network: Network,
+    ) -> Option<Action> {
+        let routing = self.config.routing.clone();
+        if routing.rules.is_empty() {
+            return None;
+        }
+
+        let is_domain = addr.parse::<IpAddr>().is_err();
+        let ips = if !is_domain || routing.needs_ips() {
+            resolve_target(&self.resolver, addr).await
         } else {
-            self.process_vmess().await
+            Vec::new()
+        };
+
+        let target = Target {
+            domain: sniffed.or(is_domain.then_some(addr)),
+            ips: &ips,
+            port,
+            network,
+            protocol: self.session.protocol,
+            user: &self.session.user,
+        };
+        routing.select(&target).cloned()
+    }
+
+    /// Waits briefly for the client's first payload so it can be replayed to
+    /// whichever outbound ends up carrying the session.
+    async fn first_payload(&mut self) -> Result<Bytes> {
+        if self.buffer.is_empty() {
+            let fill = Box::pin(self.fill_buffer_until(1));
+            if let Either::Left((res, _)) = select(fill, Delay::from(FIRST_PAYLOAD_WAIT)).await {
+                res?;
+            }
         }
 
+        Ok(self.buffer.take_all())
     }
 
-    pub async fn handle_tcp_outbound(&mut self, addr: String, port: u16) -> Result<()> {
-        let mut remote_socket = Socket::builder().connect(addr, port).map_err(|e| {
-            Error::RustError(e.to_string())
-        })?;
+    /// Replays `payload` on a freshly opened socket and waits up to
+    /// `PROBE_TIMEOUT` for the first response bytes. Client data arriving in
+    /// the meantime is forwarded too, and appended to `payload` so the next
+    /// candidate is replayed the same stream. Past `MAX_REPLAY` the probe
+    /// gives up on falling back and returns no response. With an empty
+    /// payload the server may speak first, as SMTP does, so its greeting is
+    /// awaited for `SERVER_FIRST_WAIT` and silence is not an error.
+    async fn probe(
+        &mut self,
+        remote_socket: &mut Socket,
+        payload: &mut Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let upstream = |e: std::io::Error| ProxyError::Upstream(e.to_string());
+        let server_first = payload.is_empty();
+        if !server_first {
+            remote_socket.write_all(payload).await.map_err(upstream)?;
+        }
 
-        remote_socket.opened().await.map_err(|e| {
-            Error::RustError(e.to_string())
-        })?;
+        let mut deadline = Delay::from(if server_first {
+            SERVER_FIRST_WAIT
+        } else {
+            PROBE_TIMEOUT
+        });
+        let mut response = vec![0u8; FIRST_READ_SIZE];
+        let mut client_open = true;
+        loop {
+            // both reads are cancel safe, whatever the losing side read stays
+            // buffered for the next round
+            let event = {
+                let read = Box::pin(remote_socket.read(&mut response));
+                if client_open {
+                    let fill = Box::pin(self.fill_buffer_until(1));
+                    match select(select(read, fill), &mut deadline).await {
+                        Either::Left((Either::Left((res, _)), _)) => ProbeEvent::Response(res),
+                        Either::Left((Either::Right((res, _)), _)) => ProbeEvent::Client(res),
+                        Either::Right(_) => ProbeEvent::Timeout,
+                    }
+                } else {
+                    match select(read, &mut deadline).await {
+                        Either::Left((res, _)) => ProbeEvent::Response(res),
+                        Either::Right(_) => ProbeEvent::Timeout,
+                    }
+                }
+            };
 
-        tokio::io::copy_bidirectional(self, &mut remote_socket)
+            match event {
+                ProbeEvent::Response(res) => {
+                    let n = res.map_err(upstream)?;
+                    if n == 0 {
+                        return Err(ProxyError::Upstream(
+                            "remote closed before responding".to_string(),
+                        ));
+                    }
+                    response.truncate(n);
+                    return Ok(response);
+                }
+                ProbeEvent::Client(res) => {
+                    res?;
+                    let data = self.buffer.take_all();
+                    if data.is_empty() {
+                        client_open = false;
+                        continue;
+                    }
+                    remote_socket.write_all(&data).await.map_err(upstream)?;
+                    payload.extend_from_slice(&data);
+                    if payload.len() > MAX_REPLAY {
+                        return Ok(Vec::new());
+                    }
+                }
+                ProbeEvent::Timeout if server_first => return Ok(Vec::new()),
+                ProbeEvent::Timeout => {
+                    return Err(ProxyError::Upstream(format!(
+                        "no response within {}ms",
+                        PROBE_TIMEOUT.as_millis()
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Races the candidates and relays the session over the first one that
+    /// opens and answers the replayed payload, falling back to the remaining
+    /// candidates when the probe fails. The last candidate is relayed to
+    /// without waiting for an answer, since there is nothing left to fall
+    /// back to, unless the server is expected to speak first.
+    pub async fn handle_tcp_outbound(
+        &mut self,
+        mut candidates: Vec<Candidate>,
+        payload: Bytes,
+    ) -> Result<()> {
+        let mut payload = payload.to_vec();
+        loop {
+            let (candidate, mut remote_socket) =
+                connect_any(&mut candidates, self.config.timeouts.connect).await?;
+            let response = if candidates.is_empty() && !payload.is_empty() {
+                match remote_socket.write_all(&payload).await {
+                    Ok(()) => Ok(Vec::new()),
+                    Err(e) => Err(ProxyError::Upstream(e.to_string())),
+                }
+            } else {
+                self.probe(&mut remote_socket, &mut payload).await
+            };
+            let response = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    crate::log_debug!("error probing {}: {}", candidate, e);
+                    let _ = remote_socket.close().await;
+                    if candidates.is_empty() {
+                        return Err(e);
+                    }
+                    continue;
+                }
+            };
+
+            if is_smtp_banner(&response, candidate.port) {
+                if let Err(e) = self.check_protocol(Some(AppProtocol::Smtp)) {
+                    let _ = remote_socket.close().await;
+                    return Err(e);
+                }
+            }
+
+            crate::log_debug!("relaying through {}", candidate);
+            self.session.outbound = Some(candidate.strategy.to_string());
+            let res = self.relay(&mut remote_socket, &response).await;
+            let _ = remote_socket.close().await;
+            return res;
+        }
+    }
+
+    /// Relays the session both ways until each side is done. A client Close
+    /// half-closes the remote socket, and the remote's EOF closes the
+    /// websocket through `poll_shutdown`.
+    async fn relay(&mut self, remote_socket: &mut Socket, response: &[u8]) -> Result<()> {
+        self.write_all(response).await?;
+        match self.copy_bidirectional(remote_socket).await {
+            Ok(()) => Ok(()),
+            // the remote kept sending after the client closed
+            Err(e) if e.kind() == ErrorKind::BrokenPipe && self.peer_closed => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Copies data both ways until each side is done, like
+    /// `tokio::io::copy_bidirectional`. The client's messages are drained
+    /// into the read buffer on every poll though, whether or not the remote
+    /// is taking data, since the runtime queues them without limit otherwise.
+    /// A remote that falls `MAX_READ_BUFFER` behind fails the session.
+    async fn copy_bidirectional(&mut self, remote: &mut Socket) -> std::io::Result<()> {
+        let mut upload: Option<Bytes> = None;
+        let mut client_eof = false;
+        let mut upload_done = false;
+        let mut download_done = false;
+        let mut read_buf = vec![0u8; RELAY_READ_SIZE];
+        let mut download = 0..0;
+
+        poll_fn(|cx| {
+            while !client_eof {
+                match Pin::new(&mut *self).poll_frame(cx)? {
+                    Poll::Ready(true) => {}
+                    Poll::Ready(false) => client_eof = true,
+                    Poll::Pending => break,
+                }
+            }
+
+            while !upload_done {
+                if upload.is_none() {
+                    upload = self.buffer.take(usize::MAX);
+                }
+                match upload.as_mut() {
+                    Some(chunk) => match Pin::new(&mut *remote).poll_write(cx, chunk)? {
+                        Poll::Ready(0) => return Poll::Ready(Err(ErrorKind::WriteZero.into())),
+                        Poll::Ready(n) => {
+                            chunk.advance(n);
+                            if chunk.is_empty() {
+                                upload = None;
+                            }
+                        }
+                        Poll::Pending => break,
+                    },
+                    None if client_eof => match Pin::new(&mut *remote).poll_shutdown(cx)? {
+                        Poll::Ready(()) => upload_done = true,
+                        Poll::Pending => break,
+                    },
+                    None => {
+                        let _ = Pin::new(&mut *remote).poll_flush(cx)?;
+                        break;
+                    }
+                }
+            }
+
+            // remote data not yet taken by the client is kept in `download`
+            // while writes wait
+            'download: while !download_done {
+                while !download.is_empty() {
+                    match Pin::new(&mut *self).poll_write(cx, &read_buf[download.clone()])? {
+                        Poll::Ready(n) => download.start += n,
+                        Poll::Pending => break 'download,
+                    }
+                }
+
+                let mut buf = ReadBuf::new(&mut read_buf);
+                match Pin::new(&mut *remote).poll_read(cx, &mut buf)? {
+                    Poll::Ready(()) if buf.filled().is_empty() => {
+                        ready!(Pin::new(&mut *self).poll_shutdown(cx))?;
+                        download_done = true;
+                    }
+                    Poll::Ready(()) => download = 0..buf.filled().len(),
+                    Poll::Pending => {
+                        ready!(Pin::new(&mut *self).poll_flush(cx))?;
+                        break;
+                    }
+                }
+            }
+
+            if upload_done && download_done {
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Answers a client's dns query through the shared resolver.
+    async fn resolve_dns(&self, query: &[u8]) -> Result<Vec<u8>> {
+        metrics::inc(metrics::DNS_QUERIES, &[("source", "tunnel")]);
+        self.resolver
+            .exchange(query)
             .await
-            .map_err(|e| {
-                Error::RustError(e.to_string())
-            })?;
-        Ok(())
+            .map_err(|e| ProxyError::Dns(e.to_string()))
     }
 
-    pub async fn handle_udp_outbound(&mut self) -> Result<()> {
-        let mut buff = vec![0u8; 65535];
+    /// Serves dns over tcp (RFC 1035 4.2.2): every message is prefixed with
+    /// its 2 bytes length, and a client may send many queries on one stream.
+    pub async fn handle_dns_stream(&mut self) -> Result<()> {
+        loop {
+            let len = match self.read_u16().await {
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let mut query = vec![0u8; len as _];
+            self.read_exact(&mut query).await?;
+
+            let response = self.resolve_dns(&query).await?;
+            let mut framed = Vec::with_capacity(response.len() + 2);
+            framed.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            framed.extend_from_slice(&response);
+            self.write_all(&framed).await?;
+            self.flush().await?;
+        }
+    }
 
-        let n = self.read(&mut buff).await?;
-        let data = &buff[..n];
-        if crate::dns::doh(data).await.is_ok() {
-            self.write(&data).await?;
-        };
+    pub async fn handle_udp_outbound(&mut self, port: u16) -> Result<()> {
+        let packet = self.read_chunk().await?;
+        self.check_protocol(classify(&packet, port, Network::Udp))?;
+        let response = self.resolve_dns(&packet).await?;
+        self.write_all(&response).await?;
+        self.flush().await?;
         Ok(())
     }
 }
 
 impl<'a> AsyncRead for ProxyStream<'a> {
     fn poll_read(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<tokio::io::Result<()>> {
-        let mut this = self.project();
-
         loop {
-            let size = std::cmp::min(this.buffer.len(), buf.remaining());
-            if size > 0 {
-                buf.put_slice(&this.buffer.split_to(size));
+            if let Some(chunk) = self.as_mut().project().buffer.take(buf.remaining()) {
+                buf.put_slice(&chunk);
                 return Poll::Ready(Ok(()));
             }
 
-            match this.events.as_mut().poll_next(cx) {
-                Poll::Ready(Some(Ok(WebsocketEvent::Message(msg)))) => {
-                    msg.bytes().iter().for_each(|x| this.buffer.put_slice(&x));
-                }
-                Poll::Pending => return Poll::Pending,
-                _ => return Poll::Ready(Ok(())),
+            if !ready!(self.as_mut().poll_frame(cx))? {
+                return Poll::Ready(Ok(()));
             }
         }
     }
 }
 
 impl<'a> AsyncWrite for ProxyStream<'a> {
+    /// Buffers `buf` into the current frame, sending it once full. workerd
+    /// doesn't report the websocket's unsent data (`bufferedAmount` always
+    /// reads 0), so once `MAX_UNSENT` bytes went out the write waits for a
+    /// yield to the event loop, which lets the runtime write them.
     fn poll_write(
         self: Pin<&mut Self>,
-        _: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<tokio::io::Result<usize>> {
-        return Poll::Ready(
-            self.ws
-                .send_with_bytes(buf)
-                .map(|_| buf.len())
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
-        );
+        let this = self.project();
+        if *this.closed || *this.peer_closed {
+            return Poll::Ready(Err(websocket_closed()));
+        }
+        if this.write_buffer.poll_ready(cx).is_pending() {
+            let drain = this
+                .drain
+                .get_or_insert_with(|| Delay::from(Duration::ZERO));
+            ready!(Pin::new(drain).poll(cx));
+            *this.drain = None;
+            this.write_buffer.drained();
+        }
+
+        this.deadlines.touch();
+        let n = this.write_buffer.push(buf);
+        this.session.bytes_down += n as u64;
+        if let Some(frame) = this.write_buffer.full_frame() {
+            send_frame(this.ws, &frame)?;
+        }
+        Poll::Ready(Ok(n))
     }
 
     fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
-        Poll::Ready(Ok(()))
+        let this = self.project();
+        match this.write_buffer.frame() {
+            Some(_) if *this.closed || *this.peer_closed => Poll::Ready(Err(websocket_closed())),
+            Some(frame) => Poll::Ready(send_frame(this.ws, &frame)),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        let flushed = self.as_mut().poll_flush(cx);
+        let this = self.project();
+        close_ws(this.ws, this.closed, CLOSE_NORMAL, "remote closed");
+        flushed
     }
+}
 
-    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
-        unimplemented!()
+fn send_frame(ws: &WebSocket, frame: &[u8]) -> std::io::Result<()> {
+    ws.send_with_bytes(frame)
+        .map_err(|e| ClientError::new(ErrorKind::BrokenPipe, e.to_string()).into())
+}
+
+fn websocket_closed() -> std::io::Error {
+    ClientError::new(ErrorKind::BrokenPipe, "websocket closed").into()
+}
+
+fn close_ws(ws: &WebSocket, closed: &mut bool, code: u16, reason: &str) {
+    if std::mem::replace(closed, true) {
+        return;
+    }
+
+    let mut end = reason.len().min(MAX_CLOSE_REASON);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    // fails when the client already went away, which is fine
+    if let Err(e) = ws.close(Some(code), Some(&reason[..end])) {
+        console_debug!("error closing websocket: {}", e);
     }
 }
@@ -0,0 +1,291 @@
+use super::message::{Message, Record, CLASS_IN, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA};
+use super::Resolve;
+use crate::proxy::metrics;
+use crate::proxy::privacy::Dest;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use anyhow::Result;
+use worker::{console_error, Env};
+
+/// KV namespace holding additional lists, keyed by list name.
+pub const BLOCKLISTS_BINDING: &str = "BLOCKLISTS";
+
+/// Lists compiled into the worker.
+const EMBEDDED: &[(&str, &str)] = &[
+    ("ads", include_str!("../../../config/blocklists/ads.txt")),
+    ("trackers", include_str!("../../../config/blocklists/trackers.txt")),
+];
+
+/// Ttl of synthesized block responses, in seconds.
+const BLOCK_TTL: u32 = 300;
+
+thread_local! {
+    static LOADED: RefCell<HashMap<String, Rc<Blocklist>>> = RefCell::new(HashMap::new());
+    static BLOCK_COUNTS: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+}
+
+/// How a blocked query is answered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlockMode {
+    #[default]
+    NxDomain,
+    /// `0.0.0.0` for A, `::` for AAAA and an empty answer otherwise.
+    Zero,
+}
+
+impl FromStr for BlockMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "nxdomain" => Ok(Self::NxDomain),
+            "zero" => Ok(Self::Zero),
+            _ => Err(format!("invalid block mode: {}", s)),
+        }
+    }
+}
+
+/// A set of blocked domains parsed from hosts-file (`0.0.0.0 example.com`)
+/// or adblock-style (`||example.com^`) lines. Hosts entries match exactly,
+/// adblock entries also match every subdomain.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    pub name: String,
+    exact: HashSet<String>,
+    suffix: HashSet<String>,
+}
+
+impl Blocklist {
+    pub fn parse(name: &str, text: &str) -> Self {
+        let mut list = Self {
+            name: name.to_string(),
+            ..Default::default()
+        };
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(['#', '!', '@', '[']) {
+                continue;
+            }
+
+            if let Some(rule) = line.strip_prefix("||") {
+                // only plain domain rules apply to dns, skip path or option rules
+                let domain = rule.trim_end_matches('^');
+                if !domain.is_empty() && domain.chars().all(is_domain_char) {
+                    list.suffix.insert(domain.to_ascii_lowercase());
+                }
+                continue;
+            }
+
+            // hosts lines map an address to any number of domains, anything
+            // else is a bare domain
+            let line = line.split('#').next().unwrap_or_default();
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let domains = match fields[..] {
+                [_] => &fields[..],
+                [_, ref domains @ ..] => domains,
+                [] => continue,
+            };
+            for domain in domains {
+                if domain.chars().all(is_domain_char) && *domain != "localhost" {
+                    list.exact.insert(domain.to_ascii_lowercase());
+                }
+            }
+        }
+
+        list
+    }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if self.exact.contains(&domain) {
+            return true;
+        }
+
+        let mut rest = domain.as_str();
+        loop {
+            if self.suffix.contains(rest) {
+                return true;
+            }
+            match rest.split_once('.') {
+                Some((_, parent)) => rest = parent,
+                None => return false,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.suffix.len()
+    }
+}
+
+fn is_domain_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_'
+}
+
+/// Loads the named lists, embedded ones first, then from the `BLOCKLISTS` kv
+/// namespace. Parsed lists stay in memory for the lifetime of the isolate.
+pub async fn load(env: &Env, names: &[String]) -> Vec<Rc<Blocklist>> {
+    let mut lists = Vec::with_capacity(names.len());
+    for name in names {
+        if let Some(list) = LOADED.with(|x| x.borrow().get(name).cloned()) {
+            lists.push(list);
+            continue;
+        }
+
+        let text = match EMBEDDED.iter().find(|(x, _)| x == name) {
+            Some((_, text)) => Some(text.to_string()),
+            None => match env.kv(BLOCKLISTS_BINDING) {
+                Ok(kv) => kv.get(name).text().await.unwrap_or_else(|e| {
+                    console_error!("error loading blocklist {}: {}", name, e);
+                    None
+                }),
+                Err(_) => None,
+            },
+        };
+
+        match text {
+            Some(text) => {
+                let list = Rc::new(Blocklist::parse(name, &text));
+                crate::log_debug!("loaded blocklist {} ({} domains)", name, list.len());
+                LOADED.with(|x| x.borrow_mut().insert(name.clone(), list.clone()));
+                lists.push(list);
+            }
+            None => console_error!("unknown blocklist: {}", name),
+        }
+    }
+
+    lists
+}
+
+/// Counts a blocked query against `list`, returning the isolate's total.
+fn record_block(list: &str) -> u64 {
+    metrics::inc(metrics::DNS_BLOCKED, &[("list", list)]);
+    BLOCK_COUNTS.with(|x| {
+        let mut counts = x.borrow_mut();
+        let count = counts.entry(list.to_string()).or_default();
+        *count += 1;
+        *count
+    })
+}
+
+/// Builds the answer for a blocked query.
+pub fn block_response(query: &Message, mode: BlockMode) -> Message {
+    let answers = match mode {
+        BlockMode::NxDomain => Vec::new(),
+        BlockMode::Zero => query
+            .questions
+            .iter()
+            .filter_map(|q| {
+                let data = match q.qtype {
+                    TYPE_A => vec![0; 4],
+                    TYPE_AAAA => vec![0; 16],
+                    _ => return None,
+                };
+                Some(Record {
+                    name: q.name.clone(),
+                    rtype: q.qtype,
+                    class: CLASS_IN,
+                    ttl: BLOCK_TTL,
+                    data,
+                })
+            })
+            .collect(),
+    };
+    let rcode = match mode {
+        BlockMode::NxDomain => RCODE_NXDOMAIN as u16,
+        BlockMode::Zero => 0,
+    };
+
+    Message {
+        id: query.id,
+        // response, recursion desired and available
+        flags: 0x8180 | rcode,
+        questions: query.questions.clone(),
+        answers,
+    }
+}
+
+/// Answers queries for blocked domains locally and passes the rest on.
+pub struct FilteredResolver<R> {
+    inner: R,
+    lists: Vec<Rc<Blocklist>>,
+    mode: BlockMode,
+}
+
+impl<R: Resolve> FilteredResolver<R> {
+    pub fn new(inner: R, lists: Vec<Rc<Blocklist>>, mode: BlockMode) -> Self {
+        Self { inner, lists, mode }
+    }
+
+    fn blocked_by(&self, msg: &Message) -> Option<&Blocklist> {
+        let q = msg.questions.first()?;
+        self.lists.iter().find(|x| x.matches(&q.name)).map(|x| x.as_ref())
+    }
+}
+
+impl<R: Resolve> Resolve for FilteredResolver<R> {
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        if !self.lists.is_empty() {
+            let msg = Message::parse(query)?;
+            if let Some(list) = self.blocked_by(&msg) {
+                let count = record_block(&list.name);
+                crate::log_info!(
+                    "[blocklist:{}] blocked {} ({} so far)",
+                    list.name,
+                    Dest::host(&msg.questions[0].name),
+                    count
+                );
+                return block_response(&msg, self.mode).encode();
+            }
+        }
+
+        self.inner.exchange(query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocklist() {
+        let list = Blocklist::parse(
+            "test",
+            "# comment\n0.0.0.0 ads.example.com\n127.0.0.1 localhost\n||tracker.net^\n||cdn.com/ads.js\n@@||good.com^\nplain.org\n",
+        );
+        assert_eq!(list.len(), 3);
+        assert!(list.matches("ads.example.com"));
+        assert!(list.matches("ADS.example.com."));
+        assert!(!list.matches("x.ads.example.com"));
+        assert!(list.matches("tracker.net"));
+        assert!(list.matches("a.b.tracker.net"));
+        assert!(!list.matches("nottracker.net"));
+        assert!(list.matches("plain.org"));
+        assert!(!list.matches("localhost"));
+
+        let list = Blocklist::parse("hosts", "0.0.0.0 a.com b.com # c.com\n");
+        assert_eq!(list.len(), 2);
+        assert!(list.matches("a.com") && list.matches("b.com"));
+        assert!(!list.matches("c.com"));
+
+        for (name, text) in EMBEDDED {
+            assert!(Blocklist::parse(name, text).len() > 0);
+        }
+    }
+
+    #[test]
+    fn test_block_response() {
+        let query = Message::parse(&Message::query(9, "ads.example.com", TYPE_A).unwrap()).unwrap();
+
+        let nx = block_response(&query, BlockMode::NxDomain);
+        assert_eq!((nx.id, nx.rcode(), nx.answers.len()), (9, RCODE_NXDOMAIN, 0));
+
+        let zero = Message::parse(&block_response(&query, BlockMode::Zero).encode().unwrap()).unwrap();
+        assert_eq!(zero.ips().collect::<Vec<_>>(), vec!["0.0.0.0".parse::<std::net::IpAddr>().unwrap()]);
+    }
+}
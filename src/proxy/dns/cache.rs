@@ -0,0 +1,291 @@
+use super::message::{age_ttls, Message, RCODE_NOERROR, RCODE_NXDOMAIN};
+use super::Resolve;
+use crate::proxy::metrics;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use worker::{console_error, wasm_bindgen_futures, Cache, Date, Headers, Response};
+
+/// Entries kept in the in-isolate tier.
+const LRU_CAPACITY: usize = 1024;
+/// Lifetime of answer-less responses (NXDOMAIN/NODATA), in seconds.
+const NEGATIVE_TTL: u32 = 30;
+/// Upper bound on how long any response is cached, in seconds.
+const MAX_TTL: u32 = 3600;
+
+const STORED_AT_HEADER: &str = "x-dns-stored-at";
+
+thread_local! {
+    // workers are single threaded, so one table serves every session of the isolate
+    static LRU: RefCell<Lru> = RefCell::new(Lru::new(LRU_CAPACITY));
+}
+
+struct Entry {
+    response: Vec<u8>,
+    stored_at: u64,
+    expires_at: u64,
+    last_used: u64,
+}
+
+/// A small least-recently-used table of dns responses.
+pub struct Lru {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<String, Entry>,
+}
+
+impl Lru {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the cached response with its ttls aged to `now` (milliseconds).
+    pub fn get(&mut self, key: &str, now: u64) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(key)?;
+        if entry.expires_at <= now {
+            self.entries.remove(key);
+            return None;
+        }
+
+        entry.last_used = self.tick;
+        let mut response = entry.response.clone();
+        age_ttls(&mut response, ((now - entry.stored_at) / 1000) as u32).ok()?;
+        Some(response)
+    }
+
+    pub fn insert(&mut self, key: String, response: Vec<u8>, ttl: u32, now: u64) {
+        self.tick += 1;
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, x| x.expires_at > now);
+            if self.entries.len() >= self.capacity {
+                let oldest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, x)| x.last_used)
+                    .map(|(k, _)| k.clone());
+                oldest.map(|k| self.entries.remove(&k));
+            }
+        }
+
+        self.entries.insert(
+            key,
+            Entry {
+                response,
+                stored_at: now,
+                expires_at: now + ttl as u64 * 1000,
+                last_used: self.tick,
+            },
+        );
+    }
+}
+
+/// Cache key built from the question section, so queries that only differ in
+/// transaction id or case share an entry. The key is the hex SHA-256 of the
+/// lowercased wire-format question, which keeps it safe to embed in the shared
+/// cache URL. Queries with more than one question, compressed names or labels
+/// outside letters, digits and hyphens are not cached.
+pub fn cache_key(query: &[u8]) -> Option<String> {
+    if query.get(4..6)? != [0, 1] {
+        return None;
+    }
+
+    let mut question = Vec::with_capacity(query.len().saturating_sub(12));
+    let mut pos = 12;
+    loop {
+        let len = *query.get(pos)? as usize;
+        if len > 63 {
+            return None;
+        }
+        let label = query.get(pos..pos + 1 + len)?;
+        if !label[1..]
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || *b == b'-')
+        {
+            return None;
+        }
+        question.extend(label.iter().map(u8::to_ascii_lowercase));
+        pos += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    // qtype and qclass
+    question.extend_from_slice(query.get(pos..pos + 4)?);
+
+    let digest = Sha256::digest(&question);
+    Some(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// How long a response may be cached, or `None` if it must not be.
+pub fn cacheable_ttl(msg: &Message) -> Option<u32> {
+    let ttl = match (msg.rcode(), msg.min_ttl()) {
+        (RCODE_NOERROR, Some(ttl)) => ttl,
+        (RCODE_NOERROR, None) | (RCODE_NXDOMAIN, _) => NEGATIVE_TTL,
+        _ => return None,
+    };
+    (ttl > 0).then_some(ttl.min(MAX_TTL))
+}
+
+/// Wraps a resolver with a two-tier cache: an in-isolate lru in front of the
+/// Workers Cache API. Cached responses get the caller's transaction id.
+pub struct CachedResolver<R> {
+    inner: R,
+    // the Cache API only works for urls on the worker's own zone
+    cache_origin: String,
+}
+
+impl<R> CachedResolver<R> {
+    pub fn new(inner: R, host: &str) -> Self {
+        Self {
+            inner,
+            cache_origin: format!("https://{}/__dns-cache", host),
+        }
+    }
+
+    fn cache_url(&self, key: &str) -> String {
+        format!("{}/{}", self.cache_origin, key)
+    }
+
+    async fn get_shared(&self, key: &str, now: u64) -> Result<Option<Vec<u8>>> {
+        let mut response = match Cache::default().get(self.cache_url(key), true).await? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        metrics::inc(metrics::DNS_CACHE_HITS, &[("tier", "shared")]);
+
+        let stored_at = response
+            .headers()
+            .get(STORED_AT_HEADER)?
+            .and_then(|x| x.parse::<u64>().ok())
+            .unwrap_or(now);
+        let mut body = response.bytes().await?;
+        age_ttls(&mut body, (now.saturating_sub(stored_at) / 1000) as u32)?;
+        Ok(Some(body))
+    }
+
+    /// Stores the response in the colo cache in the background, so the
+    /// answer is not held up by the write.
+    fn put_shared(&self, key: &str, response: Vec<u8>, ttl: u32, now: u64) {
+        let url = self.cache_url(key);
+        wasm_bindgen_futures::spawn_local(async move {
+            if let Err(e) = put_shared(url, response, ttl, now).await {
+                console_error!("error writing dns cache: {}", e);
+            }
+        });
+    }
+}
+
+async fn put_shared(url: String, response: Vec<u8>, ttl: u32, now: u64) -> Result<()> {
+    let mut headers = Headers::new();
+    headers.set("Cache-Control", &format!("max-age={}", ttl))?;
+    headers.set(STORED_AT_HEADER, &now.to_string())?;
+    let response = Response::from_bytes(response)?.with_headers(headers);
+    Cache::default().put(url, response).await?;
+    Ok(())
+}
+
+impl<R: Resolve> Resolve for CachedResolver<R> {
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let msg = Message::parse(query)?;
+        let key = match cache_key(query) {
+            Some(key) => key,
+            None => return self.inner.exchange(query).await,
+        };
+
+        let now = Date::now().as_millis();
+        let cached = match LRU.with(|lru| lru.borrow_mut().get(&key, now)) {
+            Some(response) => {
+                metrics::inc(metrics::DNS_CACHE_HITS, &[("tier", "isolate")]);
+                Some(response)
+            }
+            None => self.get_shared(&key, now).await.unwrap_or_else(|e| {
+                console_error!("error reading dns cache: {}", e);
+                None
+            }),
+        };
+        if cached.is_none() {
+            metrics::inc(metrics::DNS_CACHE_MISSES, &[]);
+        }
+
+        let mut response = match cached {
+            Some(response) => response,
+            None => {
+                let response = self.inner.exchange(query).await?;
+                if let Some(ttl) = cacheable_ttl(&Message::parse(&response)?) {
+                    LRU.with(|lru| lru.borrow_mut().insert(key.clone(), response.clone(), ttl, now));
+                    self.put_shared(&key, response.clone(), ttl, now);
+                }
+                response
+            }
+        };
+
+        response[..2].copy_from_slice(&msg.id.to_be_bytes());
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::dns::message::TYPE_A;
+
+    #[test]
+    fn test_lru() {
+        let mut resp = Message::query(0, "example.com", TYPE_A).unwrap();
+        resp[2] = 0x81;
+        resp[7] = 1;
+        resp.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 2, 3, 4]);
+        let msg = Message::parse(&resp).unwrap();
+        let key = cache_key(&resp).unwrap();
+        assert_eq!(key.len(), 64);
+        assert!(key.bytes().all(|b| b.is_ascii_hexdigit()));
+        let upper = Message::query(7, "EXAMPLE.com", TYPE_A).unwrap();
+        assert_eq!(cache_key(&upper), Some(key));
+        assert_eq!(cacheable_ttl(&msg), Some(60));
+
+        let mut lru = Lru::new(2);
+        lru.insert("a".to_string(), resp.clone(), 60, 0);
+        let aged = lru.get("a", 10_000).unwrap();
+        assert_eq!(Message::parse(&aged).unwrap().min_ttl(), Some(50));
+        assert!(lru.get("a", 60_000).is_none());
+
+        lru.insert("a".to_string(), resp.clone(), 60, 0);
+        lru.insert("b".to_string(), resp.clone(), 60, 0);
+        lru.get("a", 1);
+        lru.insert("c".to_string(), resp, 60, 0);
+        assert_eq!(lru.entries.len(), 2);
+        assert!(lru.get("b", 1).is_none());
+        assert!(lru.get("a", 1).is_some());
+    }
+
+    #[test]
+    fn test_cache_key_rejects_non_ldh() {
+        let victim = Message::query(0, "victim.com", TYPE_A).unwrap();
+        let mut poisoned = victim[..12].to_vec();
+        for label in [&b"victim"[..], b"com/1/1#"] {
+            poisoned.push(label.len() as u8);
+            poisoned.extend_from_slice(label);
+        }
+        poisoned.extend_from_slice(&[0, 0, 1, 0, 1]);
+        let resolver = CachedResolver::new((), "example.com");
+        let url = |query: &[u8]| cache_key(query).map(|key| resolver.cache_url(&key));
+        assert!(url(&victim).is_some());
+        assert_ne!(url(&poisoned), url(&victim));
+        assert_eq!(url(&poisoned), None);
+
+        // a dot inside a label must not alias the dotted name
+        let mut dotted = victim[..12].to_vec();
+        dotted.push(10);
+        dotted.extend_from_slice(b"victim.com");
+        dotted.extend_from_slice(&[0, 0, 1, 0, 1]);
+        assert_eq!(url(&dotted), None);
+    }
+}
@@ -0,0 +1,105 @@
+use super::message::{Message, Record, CLASS_IN, TYPE_A, TYPE_AAAA};
+use super::Resolve;
+use crate::common::domain::DomainMap;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::rc::Rc;
+
+use anyhow::Result;
+
+/// Ttl of answers synthesized from the hosts table, in seconds.
+const HOSTS_TTL: u32 = 60;
+
+/// Static name to address overrides, e.g. `{"*.corp.example": ["10.0.0.5"]}`.
+pub type Hosts = DomainMap<Vec<IpAddr>>;
+
+pub fn parse_hosts(json: &str) -> Result<Hosts, String> {
+    let entries: HashMap<String, Vec<IpAddr>> =
+        serde_json::from_str(json).map_err(|e| format!("invalid hosts: {}", e))?;
+
+    let mut hosts = Hosts::new();
+    for (pattern, ips) in entries {
+        hosts.insert(&pattern, ips);
+    }
+    Ok(hosts)
+}
+
+/// Answers A and AAAA queries for names in the hosts table. A name that is
+/// pinned but has no address of the asked family gets an empty answer.
+pub fn hosts_response(query: &Message, ips: &[IpAddr]) -> Option<Message> {
+    let q = match &query.questions[..] {
+        [q] if q.qtype == TYPE_A || q.qtype == TYPE_AAAA => q,
+        _ => return None,
+    };
+
+    let answers = ips
+        .iter()
+        .filter_map(|ip| match (ip, q.qtype) {
+            (IpAddr::V4(ip), TYPE_A) => Some(ip.octets().to_vec()),
+            (IpAddr::V6(ip), TYPE_AAAA) => Some(ip.octets().to_vec()),
+            _ => None,
+        })
+        .map(|data| Record {
+            name: q.name.clone(),
+            rtype: q.qtype,
+            class: CLASS_IN,
+            ttl: HOSTS_TTL,
+            data,
+        })
+        .collect();
+
+    Some(Message {
+        id: query.id,
+        // response, authoritative, recursion desired and available
+        flags: 0x8580,
+        questions: query.questions.clone(),
+        answers,
+    })
+}
+
+/// Answers from the hosts table before asking the inner resolver.
+pub struct HostsResolver<R> {
+    inner: R,
+    hosts: Rc<Hosts>,
+}
+
+impl<R: Resolve> HostsResolver<R> {
+    pub fn new(inner: R, hosts: Rc<Hosts>) -> Self {
+        Self { inner, hosts }
+    }
+}
+
+impl<R: Resolve> Resolve for HostsResolver<R> {
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        if !self.hosts.is_empty() {
+            let msg = Message::parse(query)?;
+            let pinned = msg.questions.first().and_then(|q| self.hosts.get(&q.name));
+            if let Some(response) = pinned.and_then(|ips| hosts_response(&msg, ips)) {
+                return response.encode();
+            }
+        }
+
+        self.inner.exchange(query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hosts_response() {
+        let hosts = parse_hosts(r#"{"*.corp.example": ["10.0.0.5", "fd00::5"]}"#).unwrap();
+        let ips = hosts.get("git.corp.example").unwrap();
+
+        let query = Message::parse(&Message::query(3, "git.corp.example", TYPE_AAAA).unwrap()).unwrap();
+        let response = Message::parse(&hosts_response(&query, ips).unwrap().encode().unwrap()).unwrap();
+        assert_eq!(response.id, 3);
+        assert_eq!(response.ips().collect::<Vec<_>>(), vec!["fd00::5".parse::<IpAddr>().unwrap()]);
+
+        let query = Message::parse(&Message::query(3, "git.corp.example", 16).unwrap()).unwrap();
+        assert!(hosts_response(&query, ips).is_none());
+        assert!(parse_hosts(r#"{"a.com": ["nope"]}"#).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{anyhow, bail, Result};
+
+/// Largest message that fits the two byte length prefix of dns over tcp.
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_OPT: u16 = 41;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const CLASS_IN: u16 = 1;
+
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match (self.rtype, self.data.len()) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = self.data[..].try_into().ok()?;
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = self.data[..].try_into().ok()?;
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        }
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1
+//
+// +---------+---------+---------+---------+---------+---------+
+// |   ID    |  Flags  | QDCOUNT | ANCOUNT | NSCOUNT | ARCOUNT |
+// +---------+---------+---------+---------+---------+---------+
+// | 2 Bytes | 2 Bytes | 2 Bytes | 2 Bytes | 2 Bytes | 2 Bytes |
+// +---------+---------+---------+---------+---------+---------+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+}
+
+impl Message {
+    /// Builds a recursive query for a single question.
+    pub fn query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(12 + name.len() + 6);
+        buf.extend_from_slice(&id.to_be_bytes());
+        // standard query, recursion desired
+        buf.extend_from_slice(&0x0100u16.to_be_bytes());
+        buf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        write_name(&mut buf, name)?;
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        Ok(buf)
+    }
+
+    /// Parses the header, question and answer sections. Authority and
+    /// additional records are ignored.
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 12 {
+            bail!("dns message too short");
+        }
+
+        let id = u16::from_be_bytes([buf[0], buf[1]]);
+        let flags = u16::from_be_bytes([buf[2], buf[3]]);
+        let qdcount = u16::from_be_bytes([buf[4], buf[5]]);
+        let ancount = u16::from_be_bytes([buf[6], buf[7]]);
+
+        let mut pos = 12;
+        let mut questions = Vec::with_capacity(qdcount as _);
+        for _ in 0..qdcount {
+            let name = read_name(buf, &mut pos)?;
+            let fixed = read_slice(buf, &mut pos, 4)?;
+            questions.push(Question {
+                name,
+                qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+                qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+            });
+        }
+
+        let mut answers = Vec::with_capacity(ancount as _);
+        for _ in 0..ancount {
+            let name = read_name(buf, &mut pos)?;
+            let fixed = read_slice(buf, &mut pos, 10)?;
+            let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]);
+            answers.push(Record {
+                name,
+                rtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+                class: u16::from_be_bytes([fixed[2], fixed[3]]),
+                ttl: u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]),
+                data: read_slice(buf, &mut pos, rdlength as _)?.to_vec(),
+            });
+        }
+
+        Ok(Self {
+            id,
+            flags,
+            questions,
+            answers,
+        })
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x000f) as u8
+    }
+
+    /// Smallest ttl among the answers, if there are any.
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.answers.iter().map(|r| r.ttl).min()
+    }
+
+    pub fn ips(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.answers.iter().filter_map(Record::ip)
+    }
+
+    /// Serializes the message without name compression.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(512);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        buf.extend_from_slice(&(self.questions.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&(self.answers.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+
+        for q in &self.questions {
+            write_name(&mut buf, &q.name)?;
+            buf.extend_from_slice(&q.qtype.to_be_bytes());
+            buf.extend_from_slice(&q.qclass.to_be_bytes());
+        }
+
+        for r in &self.answers {
+            write_name(&mut buf, &r.name)?;
+            buf.extend_from_slice(&r.rtype.to_be_bytes());
+            buf.extend_from_slice(&r.class.to_be_bytes());
+            buf.extend_from_slice(&r.ttl.to_be_bytes());
+            buf.extend_from_slice(&(r.data.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&r.data);
+        }
+
+        Ok(buf)
+    }
+}
+
+/// Decrements the ttl of every record in a wire-format message by `elapsed`
+/// seconds, so cached responses report their remaining lifetime.
+pub fn age_ttls(buf: &mut [u8], elapsed: u32) -> Result<()> {
+    if buf.len() < 12 {
+        bail!("dns message too short");
+    }
+
+    let count = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]) as usize;
+    let qdcount = count(4);
+    let records = count(6) + count(8) + count(10);
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        read_name(buf, &mut pos)?;
+        read_slice(buf, &mut pos, 4)?;
+    }
+
+    for _ in 0..records {
+        read_name(buf, &mut pos)?;
+        let fixed = read_slice(buf, &mut pos, 10)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+
+        // the opt pseudo-record stores flags in the ttl field
+        if rtype != TYPE_OPT {
+            let at = pos - 6;
+            buf[at..at + 4].copy_from_slice(&ttl.saturating_sub(elapsed).to_be_bytes());
+        }
+        read_slice(buf, &mut pos, rdlength)?;
+    }
+
+    Ok(())
+}
+
+fn read_slice<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let slice = buf
+        .get(*pos..*pos + len)
+        .ok_or_else(|| anyhow!("dns message truncated"))?;
+    *pos += len;
+    Ok(slice)
+}
+
+fn read_name(buf: &[u8], pos: &mut usize) -> Result<String> {
+    let mut labels = Vec::new();
+    let mut cursor = *pos;
+    let mut jumped = false;
+    // bounds the number of compression pointers we follow
+    let mut hops = 0;
+
+    loop {
+        let len = *buf.get(cursor).ok_or_else(|| anyhow!("dns name truncated"))? as usize;
+        match len {
+            0 => {
+                cursor += 1;
+                break;
+            }
+            l if l & 0xc0 == 0xc0 => {
+                let low = *buf.get(cursor + 1).ok_or_else(|| anyhow!("dns name truncated"))?;
+                if !jumped {
+                    *pos = cursor + 2;
+                }
+                jumped = true;
+                hops += 1;
+                if hops > 16 {
+                    bail!("dns name compression loop");
+                }
+                cursor = ((l & 0x3f) << 8) | low as usize;
+            }
+            l => {
+                let label = buf
+                    .get(cursor + 1..cursor + 1 + l)
+                    .ok_or_else(|| anyhow!("dns name truncated"))?;
+                labels.push(String::from_utf8_lossy(label).to_string());
+                cursor += 1 + l;
+            }
+        }
+    }
+
+    if !jumped {
+        *pos = cursor;
+    }
+    Ok(labels.join("."))
+}
+
+/// Writes `name` as uncompressed labels, rejecting names that do not fit
+/// the wire format (RFC 1035 2.3.4).
+pub fn write_name(buf: &mut Vec<u8>, name: &str) -> Result<()> {
+    let start = buf.len();
+    for label in name.trim_end_matches('.').split('.').filter(|x| !x.is_empty()) {
+        if label.len() > MAX_LABEL_LEN {
+            bail!("dns label longer than {} bytes", MAX_LABEL_LEN);
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    if buf.len() - start > MAX_NAME_LEN {
+        bail!("dns name longer than {} bytes", MAX_NAME_LEN);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let mut resp = Message::query(0xbeef, "example.com", TYPE_A).unwrap();
+        // flip to a response with one answer pointing back at the question name
+        resp[2] = 0x81;
+        resp[3] = 0x80;
+        resp[7] = 1;
+        resp.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 93, 184, 216, 34]);
+
+        let msg = Message::parse(&resp).unwrap();
+        assert_eq!(msg.id, 0xbeef);
+        assert_eq!(msg.questions[0].name, "example.com");
+        assert_eq!(msg.answers[0].name, "example.com");
+        assert_eq!(msg.answers[0].ttl, 3600);
+        assert_eq!(msg.ips().collect::<Vec<_>>(), vec!["93.184.216.34".parse::<IpAddr>().unwrap()]);
+
+        assert!(Message::parse(&resp[..resp.len() - 1]).is_err());
+        assert_eq!(Message::parse(&msg.encode().unwrap()).unwrap(), msg);
+
+        age_ttls(&mut resp, 600).unwrap();
+        assert_eq!(Message::parse(&resp).unwrap().min_ttl(), Some(3000));
+
+        let label = "a".repeat(64);
+        assert!(Message::query(0, &format!("{}.example.com", label), TYPE_A).is_err());
+        assert!(Message::query(0, &format!("{}.com", &label[1..]), TYPE_A).is_ok());
+        assert!(Message::query(0, &[&label[1..]; 4].join("."), TYPE_A).is_err());
+    }
+}
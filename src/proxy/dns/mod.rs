@@ -0,0 +1,30 @@
+use crate::config::Config;
+
+pub mod blocklist;
+pub mod cache;
+pub mod hosts;
+pub mod message;
+pub mod resolver;
+
+pub use blocklist::{BlockMode, Blocklist, FilteredResolver};
+pub use cache::CachedResolver;
+pub use hosts::{Hosts, HostsResolver};
+pub use resolver::{DnsRules, Resolve, Resolver, Upstream};
+
+/// The resolver chain every session uses: the hosts table, blocklists, the
+/// cache, then the upstreams.
+pub type SessionResolver = HostsResolver<FilteredResolver<CachedResolver<Resolver>>>;
+
+pub fn session_resolver(config: &Config) -> SessionResolver {
+    let upstreams = Resolver::new(
+        config.dns_upstreams.clone(),
+        config.dns_rules.clone(),
+        config.dns_timeout,
+    );
+    let filtered = FilteredResolver::new(
+        CachedResolver::new(upstreams, &config.host),
+        config.blocklists.clone(),
+        config.block_mode,
+    );
+    HostsResolver::new(filtered, config.hosts.clone())
+}
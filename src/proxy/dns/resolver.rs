@@ -0,0 +1,413 @@
+use super::message::{
+    write_name, Message, Question, Record, CLASS_IN, TYPE_A, TYPE_AAAA, TYPE_CNAME, TYPE_NS,
+    TYPE_PTR,
+};
+
+use crate::common::domain::DomainMap;
+
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use futures_util::future::{select, Either};
+use reqwest::header::{HeaderValue, ACCEPT, CONTENT_TYPE};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use worker::{console_error, Delay, SecureTransport, Socket};
+
+pub const DNS_MESSAGE: &str = "application/dns-message";
+const DNS_JSON: &str = "application/dns-json";
+/// Name queried when checking that an upstream answers.
+const CHECK_NAME: &str = "cloudflare.com";
+
+pub const DEFAULT_UPSTREAM: &str = "https://1.1.1.1/dns-query";
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A DNS upstream, written as a url:
+///
+/// - `https://host/dns-query` DoH with POST
+/// - `https+get://host/dns-query` DoH with GET
+/// - `https+json://host/dns-query` DoH with the JSON api
+/// - `tls://host:853` DNS-over-TLS, over a socket, so it cannot reach
+///   Cloudflare addresses such as 1.1.1.1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Upstream {
+    DohPost(String),
+    DohGet(String),
+    DohJson(String),
+    Dot(String, u16),
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| format!("invalid dns upstream: {}", s))?;
+
+        match scheme {
+            "https" => Ok(Self::DohPost(s.to_string())),
+            "https+get" => Ok(Self::DohGet(format!("https://{}", rest))),
+            "https+json" => Ok(Self::DohJson(format!("https://{}", rest))),
+            "tls" => {
+                let (host, port) = match rest.rsplit_once(':') {
+                    Some((host, port)) if !host.ends_with(':') => (
+                        host,
+                        port.parse()
+                            .map_err(|_| format!("invalid dns upstream: {}", s))?,
+                    ),
+                    _ => (rest, 853),
+                };
+                Ok(Self::Dot(host.to_string(), port))
+            }
+            _ => Err(format!("invalid dns upstream: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DohPost(url) => write!(f, "{}", url),
+            Self::DohGet(url) => write!(f, "{}", url.replacen("https", "https+get", 1)),
+            Self::DohJson(url) => write!(f, "{}", url.replacen("https", "https+json", 1)),
+            Self::Dot(host, port) => write!(f, "tls://{}:{}", host, port),
+        }
+    }
+}
+
+/// Anything that can answer a wire-format DNS query.
+pub trait Resolve {
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>>;
+
+    /// Resolves both A and AAAA records of `domain`, keeping whichever
+    /// family answered when the other one fails.
+    async fn lookup(&self, domain: &str) -> Result<Vec<IpAddr>> {
+        // rfc8484 recommends a zero id so responses stay cacheable
+        let (v4, v6) = futures_util::future::join(
+            self.exchange(&Message::query(0, domain, TYPE_A)?),
+            self.exchange(&Message::query(0, domain, TYPE_AAAA)?),
+        )
+        .await;
+
+        let mut ips = Vec::new();
+        let mut error = None;
+        for resp in [v4, v6] {
+            match resp.and_then(|x| Message::parse(&x)) {
+                Ok(msg) => ips.extend(msg.ips()),
+                Err(e) => error = Some(e),
+            }
+        }
+
+        match error {
+            Some(e) if ips.is_empty() => Err(e),
+            _ => Ok(ips),
+        }
+    }
+}
+
+/// Per-suffix upstreams for split-horizon dns, e.g. `*.corp.example` to an
+/// internal DoH server.
+pub type DnsRules = DomainMap<Vec<Upstream>>;
+
+/// Queries the upstreams in order, failing over to the next one on error or
+/// timeout. Names matching a split-horizon rule use the rule's upstreams.
+pub struct Resolver {
+    upstreams: Vec<Upstream>,
+    rules: Rc<DnsRules>,
+    timeout: Duration,
+    client: Client,
+}
+
+impl Resolver {
+    pub fn new(upstreams: Vec<Upstream>, rules: Rc<DnsRules>, timeout: Duration) -> Self {
+        Self {
+            upstreams,
+            rules,
+            timeout,
+            client: Client::new(),
+        }
+    }
+
+    /// Sends a probe query to a single upstream, for health checks.
+    pub async fn check(&self, upstream: &Upstream) -> Result<()> {
+        let response = self
+            .exchange_with(upstream, &Message::query(0, CHECK_NAME, TYPE_A)?)
+            .await?;
+        Message::parse(&response)?;
+        Ok(())
+    }
+
+    fn upstreams_for(&self, query: &[u8]) -> &[Upstream] {
+        if self.rules.is_empty() {
+            return &self.upstreams;
+        }
+
+        Message::parse(query)
+            .ok()
+            .and_then(|msg| msg.questions.first().and_then(|q| self.rules.get(&q.name)))
+            .unwrap_or(&self.upstreams)
+    }
+
+    async fn exchange_with(&self, upstream: &Upstream, query: &[u8]) -> Result<Vec<u8>> {
+        let exchange = Box::pin(async {
+            match upstream {
+                Upstream::DohPost(url) => self.doh_post(url, query).await,
+                Upstream::DohGet(url) => self.doh_get(url, query).await,
+                Upstream::DohJson(url) => self.doh_json(url, query).await,
+                Upstream::Dot(host, port) => dot(host, *port, query).await,
+            }
+        });
+
+        match select(exchange, Delay::from(self.timeout)).await {
+            Either::Left((res, _)) => res.map_err(without_url),
+            Either::Right(_) => bail!("timed out after {}ms", self.timeout.as_millis()),
+        }
+    }
+
+    async fn doh_post(&self, url: &str, query: &[u8]) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE))
+            .header(ACCEPT, HeaderValue::from_static(DNS_MESSAGE))
+            .body(query.to_vec())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(response.to_vec())
+    }
+
+    async fn doh_get(&self, url: &str, query: &[u8]) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .get(url)
+            .query(&[("dns", URL_SAFE_NO_PAD.encode(query))])
+            .header(ACCEPT, HeaderValue::from_static(DNS_MESSAGE))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(response.to_vec())
+    }
+
+    async fn doh_json(&self, url: &str, query: &[u8]) -> Result<Vec<u8>> {
+        let query = Message::parse(query)?;
+        let question = query
+            .questions
+            .first()
+            .ok_or_else(|| anyhow!("dns query without question"))?;
+
+        let response = self
+            .client
+            .get(url)
+            .query(&[
+                ("name", question.name.clone()),
+                ("type", question.qtype.to_string()),
+            ])
+            .header(ACCEPT, HeaderValue::from_static(DNS_JSON))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let response: JsonResponse = serde_json::from_slice(&response)?;
+
+        response.into_message(query.id, question.clone()).encode()
+    }
+}
+
+impl Resolve for Resolver {
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let mut last_err = anyhow!("no dns upstream configured");
+        for upstream in self.upstreams_for(query) {
+            match self.exchange_with(upstream, query).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    console_error!("error querying {}: {}", upstream, e);
+                    last_err = e;
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+}
+
+/// Drops the request url from http errors. GET and JSON queries carry the
+/// queried name in it, which would otherwise reach logs and close reasons
+/// past the privacy policy.
+fn without_url(e: anyhow::Error) -> anyhow::Error {
+    match e.downcast::<reqwest::Error>() {
+        Ok(e) => e.without_url().into(),
+        Err(e) => e,
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7858#section-3.3
+async fn dot(host: &str, port: u16, query: &[u8]) -> Result<Vec<u8>> {
+    let mut socket = Socket::builder()
+        .secure_transport(SecureTransport::On)
+        .connect(host, port)
+        .map_err(|e| anyhow!(e.to_string()))?;
+
+    let exchange = async {
+        let len = u16::try_from(query.len())?;
+        let mut framed = Vec::with_capacity(query.len() + 2);
+        framed.extend_from_slice(&len.to_be_bytes());
+        framed.extend_from_slice(query);
+        socket.write_all(&framed).await?;
+
+        let len = socket.read_u16().await?;
+        let mut response = vec![0u8; len as _];
+        socket.read_exact(&mut response).await?;
+        Ok(response)
+    };
+
+    let res = exchange.await;
+    let _ = socket.close().await;
+    res
+}
+
+// https://developers.cloudflare.com/1.1.1.1/encryption/dns-over-https/make-api-requests/dns-json/
+#[derive(Deserialize)]
+struct JsonResponse {
+    #[serde(rename = "Status")]
+    status: u16,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<JsonRecord>,
+}
+
+#[derive(Deserialize)]
+struct JsonRecord {
+    name: String,
+    #[serde(rename = "type")]
+    rtype: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    data: String,
+}
+
+impl JsonResponse {
+    fn into_message(self, id: u16, question: Question) -> Message {
+        let answers = self
+            .answer
+            .into_iter()
+            .filter_map(|r| {
+                let data = match r.rtype {
+                    TYPE_A | TYPE_AAAA => match r.data.parse::<IpAddr>().ok()? {
+                        IpAddr::V4(ip) => ip.octets().to_vec(),
+                        IpAddr::V6(ip) => ip.octets().to_vec(),
+                    },
+                    TYPE_CNAME | TYPE_NS | TYPE_PTR => {
+                        let mut buf = Vec::new();
+                        write_name(&mut buf, &r.data).ok()?;
+                        buf
+                    }
+                    // other record types come back in presentation format
+                    _ => return None,
+                };
+                Some(Record {
+                    name: r.name,
+                    rtype: r.rtype,
+                    class: CLASS_IN,
+                    ttl: r.ttl,
+                    data,
+                })
+            })
+            .collect();
+
+        Message {
+            id,
+            // response, recursion desired and available
+            flags: 0x8180 | (self.status & 0x000f),
+            questions: vec![question],
+            answers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_upstream() {
+        assert_eq!(
+            "https://1.1.1.1/dns-query".parse(),
+            Ok(Upstream::DohPost("https://1.1.1.1/dns-query".to_string()))
+        );
+        assert_eq!(
+            "https+get://dns.google/dns-query".parse(),
+            Ok(Upstream::DohGet("https://dns.google/dns-query".to_string()))
+        );
+        assert_eq!(
+            "https+json://cloudflare-dns.com/dns-query".parse(),
+            Ok(Upstream::DohJson("https://cloudflare-dns.com/dns-query".to_string()))
+        );
+        assert_eq!("tls://dns.quad9.net".parse(), Ok(Upstream::Dot("dns.quad9.net".to_string(), 853)));
+        assert_eq!("tls://9.9.9.9:8853".parse(), Ok(Upstream::Dot("9.9.9.9".to_string(), 8853)));
+        assert!("udp://8.8.8.8".parse::<Upstream>().is_err());
+
+        let upstream: Upstream = "https+json://dns.google/resolve".parse().unwrap();
+        assert_eq!(upstream.to_string(), "https+json://dns.google/resolve");
+    }
+
+    #[test]
+    fn test_json_response() {
+        let json = r#"{"Status":0,"Answer":[
+            {"name":"www.example.com","type":5,"TTL":300,"data":"example.com."},
+            {"name":"example.com","type":1,"TTL":60,"data":"93.184.216.34"},
+            {"name":"example.com","type":16,"TTL":60,"data":"\"v=spf1 -all\""}
+        ]}"#;
+        let response: JsonResponse = serde_json::from_str(json).unwrap();
+        let question = Question {
+            name: "www.example.com".to_string(),
+            qtype: TYPE_A,
+            qclass: CLASS_IN,
+        };
+
+        let msg = Message::parse(&response.into_message(7, question).encode().unwrap()).unwrap();
+        assert_eq!(msg.id, 7);
+        assert_eq!(msg.answers.len(), 2);
+        assert_eq!(msg.ips().collect::<Vec<_>>(), vec!["93.184.216.34".parse::<IpAddr>().unwrap()]);
+    }
+
+    struct V4Only;
+
+    impl Resolve for V4Only {
+        async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+            let mut msg = Message::parse(query)?;
+            if msg.questions[0].qtype != TYPE_A {
+                anyhow::bail!("upstream failed");
+            }
+            msg.flags = 0x8180;
+            msg.answers.push(Record {
+                name: msg.questions[0].name.clone(),
+                rtype: TYPE_A,
+                class: CLASS_IN,
+                ttl: 60,
+                data: vec![192, 0, 2, 1],
+            });
+            msg.encode()
+        }
+    }
+
+    #[test]
+    fn test_lookup_partial() {
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let ips = rt.block_on(V4Only.lookup("example.com")).unwrap();
+        assert_eq!(ips, vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+    }
+}
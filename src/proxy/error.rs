@@ -0,0 +1,192 @@
+use super::acl::AclError;
+use super::log::LogLevel;
+use super::session::Protocol;
+use super::timeout::Timeout;
+
+use std::fmt;
+use std::io;
+
+/// WebSocket close codes (RFC 6455 7.4.1).
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL: u16 = 1002;
+pub const CLOSE_POLICY: u16 = 1008;
+pub const CLOSE_ERROR: u16 = 1011;
+pub const CLOSE_BAD_GATEWAY: u16 = 1014;
+
+pub type Result<T, E = ProxyError> = std::result::Result<T, E>;
+
+/// Why a session ended early.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// Malformed or unsupported protocol header.
+    Protocol(String),
+    /// The credentials in the header don't match.
+    Auth(Protocol),
+    /// The destination is denied by the acl.
+    Acl(AclError),
+    /// The session is rejected by routing or the protocol policy.
+    Blocked(String),
+    /// No outbound could be connected, with the last attempt's error.
+    Connect(String),
+    Timeout(Timeout),
+    /// The remote failed mid-session.
+    Upstream(String),
+    Dns(String),
+    /// The client closed or vanished.
+    ClientGone(String),
+}
+
+impl ProxyError {
+    pub fn close_code(&self) -> u16 {
+        match self {
+            Self::Protocol(_) => CLOSE_PROTOCOL,
+            Self::Auth(_) | Self::Acl(_) | Self::Blocked(_) => CLOSE_POLICY,
+            Self::Connect(_) | Self::Upstream(_) => CLOSE_BAD_GATEWAY,
+            Self::Timeout(_) => CLOSE_GOING_AWAY,
+            Self::Dns(_) => CLOSE_ERROR,
+            Self::ClientGone(_) => CLOSE_NORMAL,
+        }
+    }
+
+    pub fn log_level(&self) -> LogLevel {
+        match self {
+            Self::ClientGone(_) => LogLevel::Debug,
+            Self::Blocked(_) | Self::Timeout(_) | Self::Upstream(_) => LogLevel::Info,
+            Self::Protocol(_) | Self::Auth(_) | Self::Acl(_) | Self::Connect(_) => LogLevel::Warn,
+            Self::Dns(_) => LogLevel::Error,
+        }
+    }
+
+    /// Stable name of the error kind, for logs and metrics.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Protocol(_) => "protocol",
+            Self::Auth(_) => "auth",
+            Self::Acl(_) => "acl",
+            Self::Blocked(_) => "blocked",
+            Self::Connect(_) => "connect",
+            Self::Timeout(_) => "timeout",
+            Self::Upstream(_) => "upstream",
+            Self::Dns(_) => "dns",
+            Self::ClientGone(_) => "client_gone",
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Protocol(e) => write!(f, "invalid header: {}", e),
+            Self::Auth(protocol) => write!(f, "{} authentication failed", protocol),
+            Self::Acl(e) => write!(f, "{}", e),
+            Self::Blocked(e) => write!(f, "blocked: {}", e),
+            Self::Connect(e) => write!(f, "connect failed: {}", e),
+            Self::Timeout(e) => write!(f, "{}", e),
+            Self::Upstream(e) => write!(f, "upstream error: {}", e),
+            Self::Dns(e) => write!(f, "dns error: {}", e),
+            Self::ClientGone(e) => write!(f, "client gone: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+/// A failure of the client's websocket, carried in `io::Error`s so they end
+/// the session as `ClientGone` however the remote's errors are kinded.
+#[derive(Debug)]
+pub struct ClientError {
+    kind: io::ErrorKind,
+    msg: String,
+}
+
+impl ClientError {
+    pub fn new(kind: io::ErrorKind, msg: impl Into<String>) -> Self {
+        Self {
+            kind,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<ClientError> for io::Error {
+    fn from(e: ClientError) -> Self {
+        io::Error::new(e.kind, e)
+    }
+}
+
+impl From<AclError> for ProxyError {
+    fn from(e: AclError) -> Self {
+        Self::Acl(e)
+    }
+}
+
+impl From<Timeout> for ProxyError {
+    fn from(e: Timeout) -> Self {
+        Self::Timeout(e)
+    }
+}
+
+/// Stream errors carry a `Timeout` when a deadline fired and a `ClientError`
+/// when the client's websocket failed. A client closing mid-header shows up
+/// as a truncated stream; anything else is the remote's doing.
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        if let Some(timeout) = e.get_ref().and_then(|x| x.downcast_ref::<Timeout>()) {
+            return Self::Timeout(*timeout);
+        }
+        if e.get_ref().is_some_and(|x| x.is::<ClientError>()) {
+            return Self::ClientGone(e.to_string());
+        }
+
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Self::ClientGone(e.to_string()),
+            io::ErrorKind::InvalidData => Self::Protocol(e.to_string()),
+            _ => Self::Upstream(e.to_string()),
+        }
+    }
+}
+
+impl From<worker::Error> for ProxyError {
+    fn from(e: worker::Error) -> Self {
+        Self::Upstream(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_io_error() {
+        let e = ProxyError::from(io::Error::from(Timeout::Idle));
+        assert!(matches!(e, ProxyError::Timeout(Timeout::Idle)));
+        assert_eq!(e.close_code(), CLOSE_GOING_AWAY);
+        assert_eq!(e.to_string(), "idle timeout");
+
+        let e = ProxyError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert_eq!(e.label(), "client_gone");
+        assert_eq!(e.log_level(), LogLevel::Debug);
+
+        let e = ProxyError::from(io::Error::other("connection reset"));
+        assert_eq!(e.label(), "upstream");
+
+        let e = ProxyError::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert_eq!(e.label(), "upstream");
+
+        let e = ProxyError::from(io::Error::from(ClientError::new(
+            io::ErrorKind::BrokenPipe,
+            "websocket closed",
+        )));
+        assert_eq!(e.label(), "client_gone");
+        assert_eq!(e.to_string(), "client gone: websocket closed");
+    }
+}
@@ -0,0 +1,36 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use worker::Delay;
+
+/// Server side keepalive for the client websocket. Workers can't send ping
+/// frames, so an empty binary message is sent instead, which keeps NAT
+/// mappings open and makes the runtime notice a dead connection as an error
+/// or close event. It is opt-in, since only clients whose websocket
+/// transport skips empty messages can take it.
+pub struct Keepalive {
+    interval: Duration,
+    timer: Pin<Box<Delay>>,
+}
+
+impl Keepalive {
+    pub fn new(interval: Duration) -> Option<Self> {
+        (!interval.is_zero()).then(|| Self {
+            interval,
+            timer: Box::pin(Delay::from(interval)),
+        })
+    }
+
+    /// Resolves once per interval.
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.timer.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.timer = Box::pin(Delay::from(self.interval));
+        // register the waker with the new timer
+        let _ = self.timer.as_mut().poll(cx);
+        Poll::Ready(())
+    }
+}
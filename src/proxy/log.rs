@@ -0,0 +1,195 @@
+use super::privacy::redact;
+use super::session::Session;
+
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+thread_local! {
+    static LEVEL: Cell<LogLevel> = const { Cell::new(LogLevel::Info) };
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// Sets the minimum level logged by this isolate.
+    pub fn set_global(level: Self) {
+        LEVEL.with(|x| x.set(level))
+    }
+
+    pub fn enabled(self) -> bool {
+        LEVEL.with(|x| self >= x.get())
+    }
+
+    pub fn log(self, msg: &str) {
+        if !self.enabled() {
+            return;
+        }
+        match self {
+            Self::Debug => worker::console_debug!("{}", msg),
+            Self::Info => worker::console_log!("{}", msg),
+            Self::Warn => worker::console_warn!("{}", msg),
+            Self::Error => worker::console_error!("{}", msg),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Debug => write!(f, "debug"),
+            Self::Info => write!(f, "info"),
+            Self::Warn => write!(f, "warn"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "debug" => Ok(Self::Debug),
+            "info" => Ok(Self::Info),
+            "warn" | "warning" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(format!("invalid log level: {}", s)),
+        }
+    }
+}
+
+/// Logs a formatted message at debug level, skipping the formatting when
+/// debug logs are off.
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        if $crate::proxy::log::LogLevel::Debug.enabled() {
+            $crate::proxy::log::LogLevel::Debug.log(&format!($($arg)*))
+        }
+    };
+}
+
+/// Logs a formatted message at info level, skipping the formatting when info
+/// logs are off.
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if $crate::proxy::log::LogLevel::Info.enabled() {
+            $crate::proxy::log::LogLevel::Info.log(&format!($($arg)*))
+        }
+    };
+}
+
+/// How a session ended, for its access log line.
+pub struct Outcome<'a> {
+    pub level: LogLevel,
+    pub close_code: u16,
+    pub reason: &'a str,
+    /// `ProxyError::label` of the error that ended the session.
+    pub error: Option<&'static str>,
+}
+
+/// The one json line logged per tunnel session. Destinations are redacted
+/// by the privacy policy.
+#[derive(Debug, Serialize)]
+pub struct AccessLog<'a> {
+    pub level: LogLevel,
+    pub msg: &'static str,
+    pub session_id: &'a str,
+    pub client_ip: &'a str,
+    pub colo: &'a str,
+    pub country: &'a str,
+    pub user: &'a str,
+    pub protocol: String,
+    pub transport: Option<String>,
+    pub target: Option<String>,
+    pub sniffed: Option<String>,
+    pub outbound: Option<&'a str>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration_ms: u64,
+    pub close_code: u16,
+    pub close_reason: &'a str,
+    pub error: Option<&'static str>,
+}
+
+impl<'a> AccessLog<'a> {
+    pub fn new(session: &'a Session, outcome: &Outcome<'a>, now_ms: u64) -> Self {
+        Self {
+            level: outcome.level,
+            msg: "session",
+            session_id: &session.id,
+            client_ip: &session.client_ip,
+            colo: &session.colo,
+            country: &session.country,
+            user: &session.user,
+            protocol: session.protocol.to_string(),
+            transport: session.network.map(|x| x.to_string()),
+            target: session
+                .target
+                .as_ref()
+                .and_then(|(host, port)| redact(host, Some(*port))),
+            sniffed: session.sniffed.as_ref().and_then(|x| redact(x, None)),
+            outbound: session.outbound.as_deref(),
+            bytes_up: session.bytes_up,
+            bytes_down: session.bytes_down,
+            duration_ms: now_ms.saturating_sub(session.started_ms),
+            close_code: outcome.close_code,
+            close_reason: outcome.reason,
+            error: outcome.error,
+        }
+    }
+
+    pub fn emit(&self) {
+        if let Ok(line) = serde_json::to_string(self) {
+            self.level.log(&line)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::session::{Network, Protocol};
+
+    #[test]
+    fn test_access_log() {
+        let session = Session {
+            id: "0123456789abcdef".to_string(),
+            protocol: Protocol::Vless,
+            network: Some(Network::Tcp),
+            target: Some(("example.com".to_string(), 443)),
+            outbound: Some("direct".to_string()),
+            bytes_up: 10,
+            started_ms: 1000,
+            ..Default::default()
+        };
+        let outcome = Outcome {
+            level: LogLevel::Info,
+            close_code: 1000,
+            reason: "session ended",
+            error: None,
+        };
+        let log = serde_json::to_value(AccessLog::new(&session, &outcome, 1500)).unwrap();
+        assert_eq!(log["level"], "info");
+        assert_eq!(log["protocol"], "vless");
+        assert_eq!(log["transport"], "tcp");
+        assert_eq!(log["target"], "example.com:443");
+        assert_eq!(log["sniffed"], serde_json::Value::Null);
+        assert_eq!(log["duration_ms"], 500);
+
+        assert_eq!("WARNING".parse(), Ok(LogLevel::Warn));
+        assert!("verbose".parse::<LogLevel>().is_err());
+    }
+}
@@ -0,0 +1,404 @@
+//! Operational metrics in the Prometheus text format.
+//!
+//! Each isolate batches what it records and flushes the batch to the
+//! `MetricsStore` durable object, which aggregates every isolate and serves
+//! the scrape. Batches are sent in the background once `FLUSH_INTERVAL_MS`
+//! old or `FLUSH_RECORDS` large, so the object sees a request per isolate
+//! every few seconds rather than one per session. Without the `METRICS`
+//! binding the isolate keeps its own totals instead. Labels never carry
+//! destinations, so they need no redaction under the privacy policy.
+
+use super::session::Session;
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+/// Durable object namespace aggregating the metrics.
+const BINDING: &str = "METRICS";
+const STORAGE_KEY: &str = "metrics";
+/// Age at which a batch is flushed, in milliseconds.
+const FLUSH_INTERVAL_MS: u64 = 10_000;
+/// Size, in recorded values, at which a batch is flushed early.
+const FLUSH_RECORDS: u64 = 1000;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 9] = [0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub const SESSIONS: &str = "siren_sessions_total";
+pub const HANDSHAKE_FAILURES: &str = "siren_handshake_failures_total";
+pub const SESSION_ERRORS: &str = "siren_session_errors_total";
+pub const OUTBOUND_ATTEMPTS: &str = "siren_outbound_attempts_total";
+pub const CONNECT_DURATION: &str = "siren_connect_duration_seconds";
+pub const BYTES: &str = "siren_bytes_total";
+pub const DNS_QUERIES: &str = "siren_dns_queries_total";
+pub const DNS_CACHE_HITS: &str = "siren_dns_cache_hits_total";
+pub const DNS_CACHE_MISSES: &str = "siren_dns_cache_misses_total";
+pub const DNS_BLOCKED: &str = "siren_dns_blocked_total";
+
+/// Name, type and help of every metric, in exposition order.
+const DESCRIPTORS: &[(&str, &str, &str)] = &[
+    (
+        SESSIONS,
+        "counter",
+        "Tunnel sessions by inbound protocol and transport.",
+    ),
+    (
+        HANDSHAKE_FAILURES,
+        "counter",
+        "Sessions that ended before the handshake completed, by reason.",
+    ),
+    (
+        SESSION_ERRORS,
+        "counter",
+        "Sessions ended by an error after the handshake, by reason.",
+    ),
+    (
+        OUTBOUND_ATTEMPTS,
+        "counter",
+        "Outbound connection attempts by strategy and result.",
+    ),
+    (
+        CONNECT_DURATION,
+        "histogram",
+        "Time to open an outbound connection, by strategy.",
+    ),
+    (
+        BYTES,
+        "counter",
+        "Bytes received from (up) and sent to (down) clients.",
+    ),
+    (
+        DNS_QUERIES,
+        "counter",
+        "DNS queries from clients, by source.",
+    ),
+    (DNS_CACHE_HITS, "counter", "DNS cache hits by tier."),
+    (
+        DNS_CACHE_MISSES,
+        "counter",
+        "DNS lookups missing both cache tiers.",
+    ),
+    (
+        DNS_BLOCKED,
+        "counter",
+        "DNS queries answered by a blocklist, by list.",
+    ),
+];
+
+thread_local! {
+    // recorded since the last flush
+    static PENDING: RefCell<Metrics> = RefCell::new(Metrics::default());
+    static RECORDED: Cell<u64> = const { Cell::new(0) };
+    static LAST_FLUSH: Cell<u64> = const { Cell::new(0) };
+    static FLUSHING: Cell<bool> = const { Cell::new(false) };
+    // stand-in for the durable object when it isn't bound
+    static LOCAL: RefCell<Metrics> = RefCell::new(Metrics::default());
+}
+
+/// Cumulative bucket counts of a histogram, as exposed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub buckets: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        self.buckets.resize(BUCKETS.len(), 0);
+        for (count, le) in self.buckets.iter_mut().zip(BUCKETS) {
+            if value <= le {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.buckets.resize(BUCKETS.len(), 0);
+        for (count, other) in self.buckets.iter_mut().zip(&other.buckets) {
+            *count += other;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// Series keyed by their name and labels, e.g. `name{a="b"}`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metrics {
+    pub counters: BTreeMap<String, u64>,
+    pub histograms: BTreeMap<String, Histogram>,
+}
+
+impl Metrics {
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty() && self.histograms.is_empty()
+    }
+
+    pub fn add(&mut self, name: &str, labels: &[(&str, &str)], n: u64) {
+        *self.counters.entry(series(name, labels)).or_default() += n;
+    }
+
+    pub fn observe(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.histograms
+            .entry(series(name, labels))
+            .or_default()
+            .observe(value);
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (key, n) in &other.counters {
+            *self.counters.entry(key.clone()).or_default() += n;
+        }
+        for (key, histogram) in &other.histograms {
+            self.histograms
+                .entry(key.clone())
+                .or_default()
+                .merge(histogram);
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, kind, help) in DESCRIPTORS {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (key, n) in &self.counters {
+                if split(key).0 == *name {
+                    let _ = writeln!(out, "{} {}", key, n);
+                }
+            }
+            for (key, histogram) in &self.histograms {
+                let (key_name, labels) = split(key);
+                if key_name != *name {
+                    continue;
+                }
+                let bucket = |le: &str| match labels {
+                    "" => format!("{}_bucket{{le=\"{}\"}}", name, le),
+                    labels => format!("{}_bucket{{{},le=\"{}\"}}", name, labels, le),
+                };
+                for (count, le) in histogram.buckets.iter().zip(BUCKETS) {
+                    let _ = writeln!(out, "{} {}", bucket(&le.to_string()), count);
+                }
+                let _ = writeln!(out, "{} {}", bucket("+Inf"), histogram.count);
+                let suffix = |x: &str| match labels {
+                    "" => format!("{}_{}", name, x),
+                    labels => format!("{}_{}{{{}}}", name, x, labels),
+                };
+                let _ = writeln!(out, "{} {}", suffix("sum"), histogram.sum);
+                let _ = writeln!(out, "{} {}", suffix("count"), histogram.count);
+            }
+        }
+        out
+    }
+}
+
+fn series(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+/// Splits a series key into the metric name and the labels between braces.
+fn split(key: &str) -> (&str, &str) {
+    match key.split_once('{') {
+        Some((name, labels)) => (name, labels.strip_suffix('}').unwrap_or(labels)),
+        None => (key, ""),
+    }
+}
+
+pub fn inc(name: &str, labels: &[(&str, &str)]) {
+    add(name, labels, 1)
+}
+
+pub fn add(name: &str, labels: &[(&str, &str)], n: u64) {
+    RECORDED.with(|x| x.set(x.get() + 1));
+    PENDING.with(|x| x.borrow_mut().add(name, labels, n))
+}
+
+pub fn observe(name: &str, labels: &[(&str, &str)], value: f64) {
+    RECORDED.with(|x| x.set(x.get() + 1));
+    PENDING.with(|x| x.borrow_mut().observe(name, labels, value))
+}
+
+/// Records how a tunnel session went. `error` is the label of the error
+/// that ended it.
+pub fn record_session(session: &Session, error: Option<&str>) {
+    match session.network {
+        Some(network) => {
+            let protocol = session.protocol.to_string();
+            let transport = network.to_string();
+            inc(
+                SESSIONS,
+                &[("protocol", &protocol), ("transport", &transport)],
+            );
+            if let Some(error) = error {
+                inc(SESSION_ERRORS, &[("reason", error)]);
+            }
+        }
+        None => inc(HANDSHAKE_FAILURES, &[("reason", error.unwrap_or("closed"))]),
+    }
+    add(BYTES, &[("direction", "up")], session.bytes_up);
+    add(BYTES, &[("direction", "down")], session.bytes_down);
+}
+
+fn stub(env: &Env) -> Result<Stub> {
+    env.durable_object(BINDING)?
+        .id_from_name("global")?
+        .get_stub()
+}
+
+/// Flushes the batch when it is old or large enough, unless a flush is
+/// already under way. Meant for `wait_until` or a background task.
+pub async fn flush_if_due(env: Env) {
+    let now = Date::now().as_millis();
+    let since = now.saturating_sub(LAST_FLUSH.with(Cell::get));
+    let pending = PENDING.with(|x| !x.borrow().is_empty());
+    if !is_due(RECORDED.with(Cell::get), pending, since) || FLUSHING.with(|x| x.replace(true)) {
+        return;
+    }
+
+    LAST_FLUSH.with(|x| x.set(now));
+    flush(&env).await;
+    FLUSHING.with(|x| x.set(false));
+}
+
+fn is_due(recorded: u64, pending: bool, since_ms: u64) -> bool {
+    recorded >= FLUSH_RECORDS || (pending && since_ms >= FLUSH_INTERVAL_MS)
+}
+
+/// Sends what this isolate recorded to the metrics object, or into the
+/// local totals when it isn't bound. A failed send keeps the batch for the
+/// next flush.
+pub async fn flush(env: &Env) {
+    let recorded = RECORDED.with(|x| x.replace(0));
+    let pending = PENDING.with(|x| std::mem::take(&mut *x.borrow_mut()));
+    if pending.is_empty() {
+        return;
+    }
+
+    let stub = match stub(env) {
+        Ok(stub) => stub,
+        Err(_) => return LOCAL.with(|x| x.borrow_mut().merge(&pending)),
+    };
+
+    let res = async {
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(&pending)?.into()));
+        let req = Request::new_with_init("https://metrics/", &init)?;
+        stub.fetch_with_request(req).await
+    };
+    if let Err(e) = res.await {
+        console_error!("error flushing metrics: {}", e);
+        PENDING.with(|x| x.borrow_mut().merge(&pending));
+        RECORDED.with(|x| x.set(x.get() + recorded));
+    }
+}
+
+/// The exposition of every isolate's metrics.
+pub async fn scrape(env: &Env) -> Result<String> {
+    flush(env).await;
+    match stub(env) {
+        Ok(stub) => stub.fetch_with_str("https://metrics/").await?.text().await,
+        Err(_) => Ok(LOCAL.with(|x| x.borrow().render())),
+    }
+}
+
+/// Aggregates the batches flushed by every isolate.
+#[durable_object]
+pub struct MetricsStore {
+    state: State,
+    metrics: Option<Metrics>,
+}
+
+#[durable_object]
+impl DurableObject for MetricsStore {
+    fn new(state: State, _: Env) -> Self {
+        Self {
+            state,
+            metrics: None,
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        if self.metrics.is_none() {
+            let stored: Option<String> = self.state.storage().get(STORAGE_KEY).await.ok();
+            let metrics = stored.and_then(|x| serde_json::from_str(&x).ok());
+            self.metrics = Some(metrics.unwrap_or_default());
+        }
+        let metrics = self.metrics.get_or_insert_with(Metrics::default);
+
+        match req.method() {
+            Method::Post => {
+                let batch: Metrics = req.json().await?;
+                metrics.merge(&batch);
+                let stored = serde_json::to_string(metrics)?;
+                self.state.storage().put(STORAGE_KEY, stored).await?;
+                Response::empty()
+            }
+            _ => Response::ok(metrics.render()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut metrics = Metrics::default();
+        metrics.add(SESSIONS, &[("protocol", "vless"), ("transport", "tcp")], 2);
+        metrics.observe(CONNECT_DURATION, &[("strategy", "direct")], 0.07);
+
+        let mut other = Metrics::default();
+        other.add(SESSIONS, &[("protocol", "vless"), ("transport", "tcp")], 1);
+        other.observe(CONNECT_DURATION, &[("strategy", "direct")], 3.0);
+        other.add(DNS_BLOCKED, &[("list", "a\"b")], 1);
+        metrics.merge(&other);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE siren_sessions_total counter\n"));
+        assert!(text.contains("siren_sessions_total{protocol=\"vless\",transport=\"tcp\"} 3\n"));
+        assert!(text.contains(
+            "siren_connect_duration_seconds_bucket{strategy=\"direct\",le=\"0.05\"} 0\n"
+        ));
+        assert!(text
+            .contains("siren_connect_duration_seconds_bucket{strategy=\"direct\",le=\"0.1\"} 1\n"));
+        assert!(text.contains(
+            "siren_connect_duration_seconds_bucket{strategy=\"direct\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(text.contains("siren_connect_duration_seconds_count{strategy=\"direct\"} 2\n"));
+        assert!(text.contains("siren_dns_blocked_total{list=\"a\\\"b\"} 1\n"));
+
+        let json = serde_json::to_string(&metrics).unwrap();
+        assert_eq!(serde_json::from_str::<Metrics>(&json).unwrap(), metrics);
+    }
+
+    #[test]
+    fn test_is_due() {
+        assert!(!is_due(0, false, 60_000));
+        assert!(!is_due(10, true, 1_000));
+        assert!(is_due(10, true, FLUSH_INTERVAL_MS));
+        assert!(is_due(FLUSH_RECORDS, true, 0));
+    }
+}
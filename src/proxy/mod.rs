@@ -3,5 +3,18 @@ pub mod vless;
 pub mod trojan;
 pub mod shadowsocks;
 pub mod dns;
+pub mod error;
+pub mod keepalive;
+pub mod log;
+pub mod metrics;
+pub mod acl;
+pub mod buffer;
 pub mod conn;
+pub mod outbound;
+pub mod privacy;
+pub mod routing;
+pub mod session;
+pub mod sniff;
+pub mod socks;
+pub mod timeout;
 pub use conn::*;
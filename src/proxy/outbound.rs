@@ -0,0 +1,320 @@
+use super::dns::Resolve;
+use super::error::{ProxyError, Result};
+use super::metrics;
+use super::privacy::Dest;
+use super::routing::Action;
+use super::socks::{self, SocksServer};
+use super::timeout::{with_timeout, Timeout};
+use crate::common::cloudflare::is_cloudflare_ip;
+use crate::config::Config;
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use futures_util::future::{select, Either};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use worker::*;
+
+/// Delay before starting the next connection attempt while earlier ones are
+/// still pending (RFC 8305 recommends 250ms).
+const CONNECT_STAGGER: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    Direct,
+    Proxy,
+    Nat64,
+    Socks,
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Direct => write!(f, "direct"),
+            Self::Proxy => write!(f, "proxy"),
+            Self::Nat64 => write!(f, "nat64"),
+            Self::Socks => write!(f, "socks"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub strategy: Strategy,
+    pub addr: String,
+    pub port: u16,
+    /// Socks server the connection to `addr:port` is tunnelled through.
+    pub via: Option<SocksServer>,
+}
+
+impl Candidate {
+    fn new(strategy: Strategy, addr: String, port: u16) -> Self {
+        Self {
+            strategy,
+            addr,
+            port,
+            via: None,
+        }
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.via {
+            Some(via) => write!(
+                f,
+                "{} ({} {}:{})",
+                Dest::new(&self.addr, self.port),
+                self.strategy,
+                via.host,
+                via.port
+            ),
+            None => write!(f, "{} ({})", Dest::new(&self.addr, self.port), self.strategy),
+        }
+    }
+}
+
+/// Resolves the addresses behind a target. IP literals are returned as-is,
+/// domains that fail to resolve yield an empty list.
+pub async fn resolve_target(resolver: &impl Resolve, addr: &str) -> Vec<IpAddr> {
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        return vec![ip];
+    }
+
+    match resolver.lookup(addr).await {
+        Ok(ips) => ips,
+        Err(e) => {
+            console_error!("error resolving {}: {}", Dest::host(addr), e);
+            Vec::new()
+        }
+    }
+}
+
+/// Embeds an IPv4 address into the low 32 bits of a /96 NAT64 prefix (RFC 6052).
+pub fn synthesize_nat64(prefix: Ipv6Addr, ip: Ipv4Addr) -> Ipv6Addr {
+    Ipv6Addr::from((u128::from(prefix) & !0xffff_ffff) | u32::from(ip) as u128)
+}
+
+fn socket_host(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("[{}]", v6),
+    }
+}
+
+/// Builds the ordered list of outbound attempts for a tcp target, given the
+/// addresses it passed the acl with.
+///
+/// Without a routing action the target is tried direct, then through the
+/// proxy ip, then NAT64. Direct connections to Cloudflare addresses fail on
+/// Workers, so those targets skip the direct attempt. Direct attempts dial
+/// `ips` rather than the name, one per address family, so the runtime can't
+/// resolve the domain to anything else.
+pub fn tcp_candidates(
+    config: &Config,
+    action: Option<&Action>,
+    addr: String,
+    ips: &[IpAddr],
+    port: u16,
+) -> Vec<Candidate> {
+    match action {
+        Some(Action::Block) => return Vec::new(),
+        Some(Action::ProxyPool(name)) => {
+            return config.routing.pools[name]
+                .iter()
+                .map(|(host, port)| Candidate::new(Strategy::Proxy, host.clone(), *port))
+                .collect()
+        }
+        Some(Action::Socks(name)) => {
+            let mut candidate = Candidate::new(Strategy::Socks, addr, port);
+            candidate.via = Some(config.routing.socks[name].clone());
+            return vec![candidate];
+        }
+        Some(Action::Direct) | Some(Action::Nat64) | None => {}
+    }
+
+    let v4 = ips.iter().find(|ip| ip.is_ipv4()).copied();
+    let v6 = ips.iter().find(|ip| ip.is_ipv6()).copied();
+
+    let direct: Vec<_> = [v6, v4]
+        .into_iter()
+        .flatten()
+        .map(|ip| Candidate::new(Strategy::Direct, socket_host(ip), port))
+        .collect();
+
+    let nat64 = match (config.nat64_prefix, v4) {
+        (Some(prefix), Some(IpAddr::V4(v4))) => {
+            let nat64 = IpAddr::V6(synthesize_nat64(prefix, v4));
+            Some(Candidate::new(Strategy::Nat64, socket_host(nat64), port))
+        }
+        _ => None,
+    };
+
+    match action {
+        Some(Action::Direct) => return direct,
+        Some(Action::Nat64) => return nat64.into_iter().collect(),
+        _ => {}
+    }
+
+    let mut candidates = Vec::with_capacity(4);
+    if ips.iter().any(is_cloudflare_ip) {
+        crate::log_debug!(
+            "{} is hosted on cloudflare, skipping direct connect",
+            Dest::host(&addr)
+        );
+    } else {
+        candidates.extend(direct);
+    }
+
+    candidates.push(Candidate::new(
+        Strategy::Proxy,
+        config.proxy_addr.clone(),
+        config.proxy_port,
+    ));
+    candidates.extend(nat64);
+
+    candidates
+}
+
+async fn open(candidate: &Candidate) -> Result<Socket> {
+    let (host, port) = match &candidate.via {
+        Some(via) => (via.host.clone(), via.port),
+        None => (candidate.addr.clone(), candidate.port),
+    };
+    let mut socket = Socket::builder()
+        .connect(host, port)
+        .map_err(|e| ProxyError::Connect(e.to_string()))?;
+    socket
+        .opened()
+        .await
+        .map_err(|e| ProxyError::Connect(e.to_string()))?;
+
+    if let Some(via) = &candidate.via {
+        if let Err(e) = socks::handshake(&mut socket, via, &candidate.addr, candidate.port).await {
+            let _ = socket.close().await;
+            return Err(e);
+        }
+    }
+    Ok(socket)
+}
+
+/// Opens and closes a connection to a proxy, for health checks.
+pub async fn check(host: &str, port: u16, timeout: Duration) -> Result<()> {
+    let candidate = Candidate::new(Strategy::Proxy, host.to_string(), port);
+    let mut socket = match with_timeout(timeout, Timeout::Connect, open(&candidate)).await {
+        Ok(res) => res?,
+        Err(timeout) => return Err(timeout.into()),
+    };
+    let _ = socket.close().await;
+    Ok(())
+}
+
+async fn attempt(candidate: Candidate, timeout: Duration) -> (Candidate, Result<Socket>) {
+    let started = Date::now().as_millis();
+    let res = match with_timeout(timeout, Timeout::Connect, open(&candidate)).await {
+        Ok(res) => res,
+        Err(timeout) => Err(timeout.into()),
+    };
+
+    let strategy = candidate.strategy.to_string();
+    let result = match &res {
+        Ok(_) => "ok",
+        Err(e) => e.label(),
+    };
+    metrics::inc(
+        metrics::OUTBOUND_ATTEMPTS,
+        &[("strategy", &strategy), ("result", result)],
+    );
+    if res.is_ok() {
+        let elapsed = Date::now().as_millis().saturating_sub(started) as f64 / 1000.0;
+        metrics::observe(metrics::CONNECT_DURATION, &[("strategy", &strategy)], elapsed);
+    }
+    (candidate, res)
+}
+
+/// Races connections to `candidates`, starting the next attempt whenever the
+/// stagger delay elapses or an earlier attempt fails. On success the list
+/// keeps only the candidates that did not fail, in order, so a caller can
+/// retry them; attempts still in flight are closed in the background. Each
+/// attempt fails after `timeout`.
+pub async fn connect_any(
+    candidates: &mut Vec<Candidate>,
+    timeout: Duration,
+) -> Result<(Candidate, Socket)> {
+    let pool = std::mem::take(candidates);
+    // attempts are tracked by index, the list may hold duplicates
+    let mut failed = vec![false; pool.len()];
+    let mut next = 0;
+    let mut stagger = Delay::from(CONNECT_STAGGER);
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = ProxyError::Connect("no outbound candidate".to_string());
+
+    // every pass starts the next candidate, since it follows either the
+    // first attempt, an elapsed stagger delay or a failed attempt
+    loop {
+        if next < pool.len() {
+            let (i, candidate) = (next, pool[next].clone());
+            attempts.push(async move { (i, attempt(candidate, timeout).await) });
+            next += 1;
+            stagger = Delay::from(CONNECT_STAGGER);
+        }
+
+        let finished = if next < pool.len() {
+            match select(attempts.next(), &mut stagger).await {
+                Either::Left((finished, _)) => finished,
+                Either::Right(_) => continue,
+            }
+        } else {
+            attempts.next().await
+        };
+        let (i, (candidate, res)) = match finished {
+            Some(finished) => finished,
+            None => return Err(last_err),
+        };
+
+        match res {
+            Ok(socket) => {
+                candidates.extend(
+                    pool.into_iter()
+                        .enumerate()
+                        .filter(|(j, _)| *j != i && !failed[*j])
+                        .map(|(_, candidate)| candidate),
+                );
+                wasm_bindgen_futures::spawn_local(async move {
+                    while let Some((_, (_, res))) = attempts.next().await {
+                        if let Ok(mut socket) = res {
+                            let _ = socket.close().await;
+                        }
+                    }
+                });
+                return Ok((candidate, socket));
+            }
+            Err(e) => {
+                crate::log_debug!("error connecting to {}: {}", candidate, e);
+                failed[i] = true;
+                last_err = e;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_synthesize_nat64() {
+        let prefix = "64:ff9b::".parse().unwrap();
+        assert_eq!(
+            synthesize_nat64(prefix, Ipv4Addr::new(104, 16, 132, 229)),
+            "64:ff9b::6810:84e5".parse::<Ipv6Addr>().unwrap()
+        );
+
+        let prefix = "2602:fc59:b0:64::".parse().unwrap();
+        assert_eq!(
+            synthesize_nat64(prefix, Ipv4Addr::new(1, 2, 3, 4)).to_string(),
+            "2602:fc59:b0:64::102:304"
+        );
+    }
+}
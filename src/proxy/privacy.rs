@@ -0,0 +1,156 @@
+use crate::common::hash::hmac_sha256;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+thread_local! {
+    static POLICY: RefCell<Policy> = RefCell::new(Policy::default());
+}
+
+/// How much of a destination is kept in logs, errors and metrics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Privacy {
+    /// Host and port as requested.
+    #[default]
+    Full,
+    /// The host replaced by its HMAC under the privacy secret, keeping the
+    /// port, so sessions to the same host can still be correlated.
+    Hashed,
+    /// Domain names only, without ports; ip destinations are dropped.
+    DomainOnly,
+    /// Nothing about the destination.
+    Off,
+}
+
+impl fmt::Display for Privacy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "full"),
+            Self::Hashed => write!(f, "hashed"),
+            Self::DomainOnly => write!(f, "domain"),
+            Self::Off => write!(f, "off"),
+        }
+    }
+}
+
+impl FromStr for Privacy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "full" => Ok(Self::Full),
+            "hashed" => Ok(Self::Hashed),
+            "domain" | "domain-only" => Ok(Self::DomainOnly),
+            "off" => Ok(Self::Off),
+            _ => Err(format!("invalid privacy mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    pub mode: Privacy,
+    pub secret: Vec<u8>,
+}
+
+impl Policy {
+    /// Sets the policy applied by `Dest` and `redact` in this isolate.
+    pub fn set_global(policy: Self) {
+        POLICY.with(|x| *x.borrow_mut() = policy)
+    }
+
+    /// What may be recorded of `host` and `port`, if anything.
+    pub fn redact(&self, host: &str, port: Option<u16>) -> Option<String> {
+        let with_port = |host: String| match port {
+            Some(port) => format!("{}:{}", host, port),
+            None => host,
+        };
+
+        match self.mode {
+            Privacy::Full => Some(with_port(host.to_string())),
+            Privacy::Hashed => {
+                let mac = hmac_sha256(&self.secret, host.to_ascii_lowercase().as_bytes());
+                let hex: String = mac[..8].iter().map(|x| format!("{:02x}", x)).collect();
+                Some(with_port(format!("h:{}", hex)))
+            }
+            Privacy::DomainOnly if host.parse::<IpAddr>().is_err() => Some(host.to_string()),
+            Privacy::DomainOnly | Privacy::Off => None,
+        }
+    }
+}
+
+/// Applies the global policy to a destination.
+pub fn redact(host: &str, port: Option<u16>) -> Option<String> {
+    POLICY.with(|x| x.borrow().redact(host, port))
+}
+
+/// A destination formatted under the global policy, for log lines and error
+/// messages. Redacted destinations print as `-`.
+pub struct Dest<'a> {
+    pub host: &'a str,
+    pub port: Option<u16>,
+}
+
+impl<'a> Dest<'a> {
+    pub fn new(host: &'a str, port: u16) -> Self {
+        Self {
+            host,
+            port: Some(port),
+        }
+    }
+
+    pub fn host(host: &'a str) -> Self {
+        Self { host, port: None }
+    }
+}
+
+impl fmt::Display for Dest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match redact(self.host, self.port) {
+            Some(dest) => write!(f, "{}", dest),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let policy = |mode| Policy {
+            mode,
+            secret: b"secret".to_vec(),
+        };
+
+        let full = policy(Privacy::Full);
+        assert_eq!(
+            full.redact("example.com", Some(443)).unwrap(),
+            "example.com:443"
+        );
+
+        let hashed = policy(Privacy::Hashed);
+        let dest = hashed.redact("example.com", Some(443)).unwrap();
+        assert!(dest.starts_with("h:") && dest.ends_with(":443"));
+        assert!(!dest.contains("example"));
+        assert_eq!(hashed.redact("EXAMPLE.com", Some(443)).unwrap(), dest);
+        assert_ne!(hashed.redact("example.org", Some(443)).unwrap(), dest);
+
+        let domain = policy(Privacy::DomainOnly);
+        assert_eq!(
+            domain.redact("example.com", Some(443)).unwrap(),
+            "example.com"
+        );
+        assert_eq!(domain.redact("1.1.1.1", Some(443)), None);
+        assert_eq!(domain.redact("2606:4700::1111", None), None);
+
+        assert_eq!(policy(Privacy::Off).redact("example.com", Some(443)), None);
+
+        Policy::set_global(policy(Privacy::Off));
+        assert_eq!(Dest::new("example.com", 443).to_string(), "-");
+        Policy::set_global(Policy::default());
+    }
+}
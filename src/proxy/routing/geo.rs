@@ -0,0 +1,225 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::rc::Rc;
+
+use regex::Regex;
+
+mod data {
+    // generated by build.rs from data/geoip and data/geosite
+    include!(concat!(env!("OUT_DIR"), "/geo_data.rs"));
+}
+
+thread_local! {
+    static GEOIP: RefCell<HashMap<String, Rc<GeoIp>>> = RefCell::new(HashMap::new());
+    static GEOSITE: RefCell<HashMap<String, Rc<GeoSite>>> = RefCell::new(HashMap::new());
+}
+
+/// Sorted, non-overlapping address ranges of a geoip list.
+#[derive(Debug, Default)]
+pub struct GeoIp {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl GeoIp {
+    /// Decodes the `[family, prefix, address...]` records written by build.rs.
+    pub fn unpack(mut packed: &[u8]) -> Self {
+        let mut geoip = Self::default();
+        while let [family, prefix, rest @ ..] = packed {
+            let prefix = *prefix as u32;
+            match family {
+                4 => {
+                    let start = u32::from_be_bytes(rest[..4].try_into().unwrap());
+                    let mask = u32::MAX.checked_shr(prefix).unwrap_or(0);
+                    geoip.v4.push((start & !mask, start | mask));
+                    packed = &rest[4..];
+                }
+                _ => {
+                    let start = u128::from_be_bytes(rest[..16].try_into().unwrap());
+                    let mask = u128::MAX.checked_shr(prefix).unwrap_or(0);
+                    geoip.v6.push((start & !mask, start | mask));
+                    packed = &rest[16..];
+                }
+            }
+        }
+
+        merge(&mut geoip.v4);
+        merge(&mut geoip.v6);
+        geoip
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => in_ranges(&self.v4, u32::from(*ip)),
+            IpAddr::V6(ip) => in_ranges(&self.v6, u128::from(*ip)),
+        }
+    }
+}
+
+fn merge<T: Ord + Copy>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn in_ranges<T: Ord + Copy>(ranges: &[(T, T)], x: T) -> bool {
+    match ranges.partition_point(|(start, _)| *start <= x) {
+        0 => false,
+        i => x <= ranges[i - 1].1,
+    }
+}
+
+/// A geosite category in v2ray domain-list-community syntax: plain lines
+/// match the domain and its subdomains, `full:` matches exactly, `keyword:`
+/// and `regexp:` match anywhere in the name.
+#[derive(Debug, Default)]
+pub struct GeoSite {
+    full: HashSet<String>,
+    suffix: HashSet<String>,
+    keyword: Vec<String>,
+    regex: Vec<Regex>,
+}
+
+impl GeoSite {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut site = Self::default();
+        for line in text.lines() {
+            // drop comments and `@attribute` tags
+            let line = line.split('#').next().unwrap_or_default();
+            let rule = match line.split_whitespace().next() {
+                Some(rule) => rule,
+                None => continue,
+            };
+
+            match rule.split_once(':') {
+                Some(("full", domain)) => {
+                    site.full.insert(domain.to_ascii_lowercase());
+                }
+                Some(("domain", domain)) => {
+                    site.suffix.insert(domain.to_ascii_lowercase());
+                }
+                Some(("keyword", keyword)) => site.keyword.push(keyword.to_ascii_lowercase()),
+                Some(("regexp", regex)) => site.regex.push(
+                    Regex::new(regex).map_err(|e| format!("invalid regexp {}: {}", regex, e))?,
+                ),
+                Some((kind, _)) => return Err(format!("unsupported geosite rule: {}", kind)),
+                None => {
+                    site.suffix.insert(rule.to_ascii_lowercase());
+                }
+            }
+        }
+        Ok(site)
+    }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if self.full.contains(&domain) {
+            return true;
+        }
+
+        let mut rest = domain.as_str();
+        loop {
+            if self.suffix.contains(rest) {
+                return true;
+            }
+            match rest.split_once('.') {
+                Some((_, parent)) => rest = parent,
+                None => break,
+            }
+        }
+
+        self.keyword.iter().any(|x| domain.contains(x.as_str()))
+            || self.regex.iter().any(|x| x.is_match(&domain))
+    }
+}
+
+/// Returns an embedded geoip list, decoding it on first use.
+pub fn geoip(code: &str) -> Result<Rc<GeoIp>, String> {
+    let code = code.trim_start_matches("geoip:").to_ascii_lowercase();
+    if let Some(geoip) = GEOIP.with(|x| x.borrow().get(&code).cloned()) {
+        return Ok(geoip);
+    }
+
+    let (_, packed) = data::GEOIP
+        .iter()
+        .find(|(name, _)| *name == code)
+        .ok_or_else(|| format!("unknown geoip: {} (not embedded, see scripts/geo2txt.py)", code))?;
+    let geoip = Rc::new(GeoIp::unpack(packed));
+    GEOIP.with(|x| x.borrow_mut().insert(code, geoip.clone()));
+    Ok(geoip)
+}
+
+/// Returns an embedded geosite category, parsing it on first use.
+pub fn geosite(category: &str) -> Result<Rc<GeoSite>, String> {
+    let category = category.trim_start_matches("geosite:").to_ascii_lowercase();
+    if let Some(geosite) = GEOSITE.with(|x| x.borrow().get(&category).cloned()) {
+        return Ok(geosite);
+    }
+
+    let (_, text) = data::GEOSITE
+        .iter()
+        .find(|(name, _)| *name == category)
+        .ok_or_else(|| format!("unknown geosite: {} (not embedded, see scripts/geo2txt.py)", category))?;
+    let geosite = Rc::new(GeoSite::parse(text)?);
+    GEOSITE.with(|x| x.borrow_mut().insert(category, geosite.clone()));
+    Ok(geosite)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geoip() {
+        let mut packed = vec![4, 8, 10, 0, 0, 0, 4, 16, 10, 1, 0, 0, 4, 24, 192, 168, 1, 0];
+        packed.extend_from_slice(&[6, 7, 0xfc, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let geoip = GeoIp::unpack(&packed);
+        assert_eq!(geoip.v4.len(), 2);
+
+        assert!(geoip.contains(&"10.255.0.1".parse().unwrap()));
+        assert!(geoip.contains(&"192.168.1.255".parse().unwrap()));
+        assert!(!geoip.contains(&"192.168.2.1".parse().unwrap()));
+        assert!(!geoip.contains(&"9.255.255.255".parse().unwrap()));
+        assert!(geoip.contains(&"fd12::1".parse().unwrap()));
+        assert!(!geoip.contains(&"fe80::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_geosite() {
+        let site = GeoSite::parse(
+            "# ads\nexample.com @ads\nfull:ads.example.org\nkeyword:tracker\nregexp:^ad[0-9]+\\.\n",
+        )
+        .unwrap();
+        assert!(site.matches("example.com"));
+        assert!(site.matches("cdn.example.com"));
+        assert!(site.matches("ads.example.org"));
+        assert!(!site.matches("www.ads.example.org"));
+        assert!(site.matches("mytracker.net"));
+        assert!(site.matches("ad12.example.net"));
+        assert!(!site.matches("example.org"));
+        assert!(GeoSite::parse("include:other").is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "geoip", feature = "geosite"))]
+    fn test_embedded() {
+        assert!(geoip("private")
+            .unwrap()
+            .contains(&"172.20.0.1".parse().unwrap()));
+        assert!(geoip("cloudflare")
+            .unwrap()
+            .contains(&"104.16.0.1".parse().unwrap()));
+        assert!(geosite("category-ads")
+            .unwrap()
+            .matches("stats.g.doubleclick.net"));
+        assert!(geoip("geoip:PRIVATE").is_ok());
+        assert!(geoip("xx").is_err());
+    }
+}
@@ -0,0 +1,434 @@
+pub mod geo;
+
+use super::session::{Network, Protocol};
+use super::socks::SocksServer;
+use crate::common::cidr::Cidr;
+use geo::{GeoIp, GeoSite};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use regex::Regex;
+use serde::Deserialize;
+use worker::{console_error, Date, Env};
+
+/// KV namespace read for the `routing` key when the `ROUTING` variable is unset.
+pub const CONFIG_BINDING: &str = "CONFIG";
+/// How long an isolate keeps using the routing config it parsed, in
+/// milliseconds, before reading it again.
+const RELOAD_INTERVAL_MS: u64 = 60_000;
+
+thread_local! {
+    static LOADED: RefCell<Option<(u64, Rc<Routing>)>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Direct,
+    ProxyPool(String),
+    Socks(String),
+    Nat64,
+    Block,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once(':') {
+            Some(("proxy-pool", name)) => Ok(Self::ProxyPool(name.to_string())),
+            Some(("socks", name)) => Ok(Self::Socks(name.to_string())),
+            None if s.trim() == "direct" => Ok(Self::Direct),
+            None if s.trim() == "nat64" => Ok(Self::Nat64),
+            None if s.trim() == "block" => Ok(Self::Block),
+            _ => Err(format!("invalid action: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Direct => write!(f, "direct"),
+            Self::ProxyPool(name) => write!(f, "proxy-pool:{}", name),
+            Self::Socks(name) => write!(f, "socks:{}", name),
+            Self::Nat64 => write!(f, "nat64"),
+            Self::Block => write!(f, "block"),
+        }
+    }
+}
+
+/// What a rule is matched against.
+pub struct Target<'a> {
+    pub domain: Option<&'a str>,
+    pub ips: &'a [IpAddr],
+    pub port: u16,
+    pub network: Network,
+    pub protocol: Protocol,
+    pub user: &'a str,
+}
+
+/// Every non-empty matcher group must match; within a group any entry may.
+/// `domain_suffix`, `domain_keyword`, `domain_regex` and `geosite` form the
+/// domain group, `ip_cidr` and `geoip` the address group.
+#[derive(Debug)]
+pub struct Rule {
+    domain_suffix: Vec<String>,
+    domain_keyword: Vec<String>,
+    domain_regex: Vec<Regex>,
+    geosite: Vec<Rc<GeoSite>>,
+    ip_cidr: Vec<Cidr>,
+    geoip: Vec<Rc<GeoIp>>,
+    port: Vec<(u16, u16)>,
+    network: Vec<Network>,
+    protocol: Vec<Protocol>,
+    user: Vec<String>,
+    pub action: Action,
+}
+
+impl Rule {
+    fn has_domain_matchers(&self) -> bool {
+        !(self.domain_suffix.is_empty()
+            && self.domain_keyword.is_empty()
+            && self.domain_regex.is_empty()
+            && self.geosite.is_empty())
+    }
+
+    fn has_ip_matchers(&self) -> bool {
+        !(self.ip_cidr.is_empty() && self.geoip.is_empty())
+    }
+
+    fn matches_domain(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.domain_suffix.iter().any(|suffix| {
+            domain == *suffix
+                || domain
+                    .strip_suffix(suffix.as_str())
+                    .is_some_and(|x| x.ends_with('.'))
+        }) || self.domain_keyword.iter().any(|x| domain.contains(x.as_str()))
+            || self.domain_regex.iter().any(|x| x.is_match(&domain))
+            || self.geosite.iter().any(|x| x.matches(&domain))
+    }
+
+    fn matches_ip(&self, ip: &IpAddr) -> bool {
+        self.ip_cidr.iter().any(|x| x.contains(ip)) || self.geoip.iter().any(|x| x.contains(ip))
+    }
+
+    pub fn matches(&self, target: &Target) -> bool {
+        if self.has_domain_matchers() && !target.domain.is_some_and(|x| self.matches_domain(x)) {
+            return false;
+        }
+        if self.has_ip_matchers() && !target.ips.iter().any(|ip| self.matches_ip(ip)) {
+            return false;
+        }
+        if !self.port.is_empty()
+            && !self
+                .port
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&target.port))
+        {
+            return false;
+        }
+        if !self.network.is_empty() && !self.network.contains(&target.network) {
+            return false;
+        }
+        if !self.protocol.is_empty() && !self.protocol.contains(&target.protocol) {
+            return false;
+        }
+        if !self.user.is_empty() && !self.user.iter().any(|x| x == target.user) {
+            return false;
+        }
+        true
+    }
+
+    fn needs_ips(&self) -> bool {
+        self.has_ip_matchers()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Routing {
+    pub pools: HashMap<String, Vec<(String, u16)>>,
+    pub socks: HashMap<String, SocksServer>,
+    pub rules: Vec<Rule>,
+}
+
+impl Routing {
+    /// Returns the action of the first matching rule.
+    pub fn select(&self, target: &Target) -> Option<&Action> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(target))
+            .map(|rule| &rule.action)
+    }
+
+    /// Whether domain targets must be resolved before rules can be evaluated.
+    pub fn needs_ips(&self) -> bool {
+        self.rules.iter().any(Rule::needs_ips)
+    }
+
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let raw: RawRouting =
+            serde_json::from_str(json).map_err(|e| format!("invalid routing: {}", e))?;
+
+        let mut pools = HashMap::new();
+        for (name, members) in raw.pools {
+            let members = members
+                .iter()
+                .map(|x| parse_host_port(x))
+                .collect::<Result<Vec<_>, _>>()?;
+            pools.insert(name, members);
+        }
+
+        let mut socks = HashMap::new();
+        for (name, server) in raw.socks {
+            socks.insert(name, server.parse()?);
+        }
+
+        let mut rules = Vec::with_capacity(raw.rules.len());
+        for rule in raw.rules {
+            let action: Action = rule.action.parse()?;
+            match &action {
+                Action::ProxyPool(name) if !pools.contains_key(name) => {
+                    return Err(format!("unknown proxy pool: {}", name))
+                }
+                Action::Socks(name) if !socks.contains_key(name) => {
+                    return Err(format!("unknown socks server: {}", name))
+                }
+                _ => {}
+            }
+
+            rules.push(Rule {
+                domain_suffix: rule
+                    .domain_suffix
+                    .iter()
+                    .map(|x| x.trim_start_matches('.').to_ascii_lowercase())
+                    .collect(),
+                domain_keyword: rule
+                    .domain_keyword
+                    .iter()
+                    .map(|x| x.to_ascii_lowercase())
+                    .collect(),
+                domain_regex: rule
+                    .domain_regex
+                    .iter()
+                    .map(|x| Regex::new(x).map_err(|e| format!("invalid regex {}: {}", x, e)))
+                    .collect::<Result<_, _>>()?,
+                geosite: rule
+                    .geosite
+                    .iter()
+                    .map(|x| geo::geosite(x))
+                    .collect::<Result<_, _>>()?,
+                ip_cidr: rule
+                    .ip_cidr
+                    .iter()
+                    .map(|x| x.parse())
+                    .collect::<Result<_, _>>()?,
+                geoip: rule
+                    .geoip
+                    .iter()
+                    .map(|x| geo::geoip(x))
+                    .collect::<Result<_, _>>()?,
+                port: rule
+                    .port
+                    .iter()
+                    .map(PortRange::parse)
+                    .collect::<Result<_, _>>()?,
+                network: rule
+                    .network
+                    .iter()
+                    .map(|x| x.parse())
+                    .collect::<Result<_, _>>()?,
+                protocol: rule
+                    .protocol
+                    .iter()
+                    .map(|x| x.parse())
+                    .collect::<Result<_, _>>()?,
+                user: rule.user,
+                action,
+            });
+        }
+
+        Ok(Self {
+            pools,
+            socks,
+            rules,
+        })
+    }
+}
+
+/// Reads the routing config from the `ROUTING` variable, or else from the
+/// `routing` key of the `CONFIG` kv namespace. Invalid configs are logged and
+/// ignored. The parsed config is shared by the isolate's sessions and read
+/// again after `RELOAD_INTERVAL_MS`.
+pub async fn load(env: &Env) -> Rc<Routing> {
+    let now = Date::now().as_millis();
+    let cached = LOADED.with(|x| {
+        x.borrow()
+            .as_ref()
+            .filter(|(loaded_at, _)| now.saturating_sub(*loaded_at) < RELOAD_INTERVAL_MS)
+            .map(|(_, routing)| routing.clone())
+    });
+    if let Some(routing) = cached {
+        return routing;
+    }
+
+    let routing = Rc::new(read(env).await);
+    LOADED.with(|x| *x.borrow_mut() = Some((now, routing.clone())));
+    routing
+}
+
+async fn read(env: &Env) -> Routing {
+    let json = match env.var("ROUTING") {
+        Ok(x) => Some(x.to_string()),
+        Err(_) => match env.kv(CONFIG_BINDING) {
+            Ok(kv) => kv.get("routing").text().await.unwrap_or_else(|e| {
+                console_error!("error loading routing: {}", e);
+                None
+            }),
+            Err(_) => None,
+        },
+    };
+
+    match json.map(|x| Routing::parse(&x)) {
+        Some(Ok(routing)) => routing,
+        Some(Err(e)) => {
+            console_error!("{}", e);
+            Routing::default()
+        }
+        None => Routing::default(),
+    }
+}
+
+/// Splits `host:port`, accepting bracketed IPv6 hosts.
+pub fn parse_host_port(s: &str) -> Result<(String, u16), String> {
+    let (host, port) = s
+        .trim()
+        .rsplit_once(':')
+        .ok_or_else(|| format!("invalid address: {}", s))?;
+    let port = port.parse().map_err(|_| format!("invalid address: {}", s))?;
+    Ok((host.to_string(), port))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRange {
+    Single(u16),
+    Range(String),
+}
+
+impl PortRange {
+    fn parse(&self) -> Result<(u16, u16), String> {
+        let invalid = || "invalid port range".to_string();
+        match self {
+            Self::Single(port) => Ok((*port, *port)),
+            Self::Range(s) => match s.split_once('-') {
+                Some((start, end)) => {
+                    let start = start.trim().parse().map_err(|_| invalid())?;
+                    let end = end.trim().parse().map_err(|_| invalid())?;
+                    if start > end {
+                        return Err(invalid());
+                    }
+                    Ok((start, end))
+                }
+                None => {
+                    let port = s.trim().parse().map_err(|_| invalid())?;
+                    Ok((port, port))
+                }
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawRouting {
+    #[serde(default)]
+    pools: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    socks: HashMap<String, String>,
+    #[serde(default)]
+    rules: Vec<RawRule>,
+}
+
+#[derive(Deserialize)]
+struct RawRule {
+    #[serde(default)]
+    domain_suffix: Vec<String>,
+    #[serde(default)]
+    domain_keyword: Vec<String>,
+    #[serde(default)]
+    domain_regex: Vec<String>,
+    #[serde(default)]
+    geosite: Vec<String>,
+    #[serde(default)]
+    ip_cidr: Vec<String>,
+    #[serde(default)]
+    geoip: Vec<String>,
+    #[serde(default)]
+    port: Vec<PortRange>,
+    #[serde(default)]
+    network: Vec<String>,
+    #[serde(default)]
+    protocol: Vec<String>,
+    #[serde(default)]
+    user: Vec<String>,
+    action: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTING: &str = r#"{
+        "pools": {"sg": ["1.2.3.4:443", "[2001:db8::1]:8443"]},
+        "socks": {"home": "alice:secret@home.example:1080"},
+        "rules": [
+            {"port": [25, "6881-6889"], "action": "block"},
+            {"domain_suffix": ["netflix.com"], "network": ["tcp"], "action": "proxy-pool:sg"},
+            {"domain_keyword": ["intranet"], "user": ["alice"], "action": "socks:home"},
+            {"domain_regex": ["^ads?\\."], "action": "block"},
+            {"ip_cidr": ["10.0.0.0/8"], "protocol": ["vless"], "action": "nat64"}
+        ]
+    }"#;
+
+    fn target<'a>(domain: Option<&'a str>, ips: &'a [IpAddr], port: u16) -> Target<'a> {
+        Target {
+            domain,
+            ips,
+            port,
+            network: Network::Tcp,
+            protocol: Protocol::Vless,
+            user: "alice",
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let routing = Routing::parse(ROUTING).unwrap();
+        assert_eq!(routing.pools["sg"][1], ("[2001:db8::1]".to_string(), 8443));
+        assert!(routing.needs_ips());
+
+        let select = |t: Target| routing.select(&t).cloned();
+        assert_eq!(select(target(Some("smtp.example"), &[], 25)), Some(Action::Block));
+        assert_eq!(select(target(None, &[], 6885)), Some(Action::Block));
+        assert_eq!(
+            select(target(Some("www.netflix.com"), &[], 443)),
+            Some(Action::ProxyPool("sg".to_string()))
+        );
+        assert_eq!(select(target(Some("notnetflix.com"), &[], 443)), None);
+        assert_eq!(
+            select(target(Some("git.intranet.example"), &[], 443)),
+            Some(Action::Socks("home".to_string()))
+        );
+        assert_eq!(select(target(Some("ad.example"), &[], 443)), Some(Action::Block));
+        let ips = ["10.1.2.3".parse().unwrap()];
+        assert_eq!(select(target(Some("x.example"), &ips, 443)), Some(Action::Nat64));
+
+        assert!(Routing::parse(r#"{"rules": [{"action": "proxy-pool:nope"}]}"#).is_err());
+        assert!(Routing::parse(r#"{"rules": [{"action": "teleport"}]}"#).is_err());
+        assert!(Routing::parse(r#"{"rules": [{"port": ["9000-80"], "action": "block"}]}"#).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Unknown,
+    Vless,
+    Vmess,
+    Trojan,
+    Shadowsocks,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "unknown"),
+            Self::Vless => write!(f, "vless"),
+            Self::Vmess => write!(f, "vmess"),
+            Self::Trojan => write!(f, "trojan"),
+            Self::Shadowsocks => write!(f, "shadowsocks"),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "vless" => Ok(Self::Vless),
+            "vmess" => Ok(Self::Vmess),
+            "trojan" => Ok(Self::Trojan),
+            "shadowsocks" | "ss" => Ok(Self::Shadowsocks),
+            _ => Err(format!("invalid protocol: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Network {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            _ => Err(format!("invalid network: {}", s)),
+        }
+    }
+}
+
+/// What is known about a tunnel session: who the client is, what the
+/// inbound handshake told us and where the traffic went. Logged once at the
+/// end of the session.
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    pub id: String,
+    /// From `CF-Connecting-IP`.
+    pub client_ip: String,
+    pub colo: String,
+    pub country: String,
+    pub protocol: Protocol,
+    pub user: String,
+    pub network: Option<Network>,
+    /// Host and port requested by the client.
+    pub target: Option<(String, u16)>,
+    pub sniffed: Option<String>,
+    /// Outbound strategy that carried the session, or `dns` when it was
+    /// answered locally.
+    pub outbound: Option<String>,
+    /// Bytes received from and sent to the client.
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub started_ms: u64,
+}
+
+impl Session {
+    /// A random 64 bits session id, hex encoded.
+    pub fn new_id() -> String {
+        let mut id = [0u8; 8];
+        let _ = getrandom::getrandom(&mut id);
+        id.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+}
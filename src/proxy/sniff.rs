@@ -0,0 +1,344 @@
+use super::session::Network;
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXT_SERVER_NAME: u16 = 0x0000;
+
+const BITTORRENT_HANDSHAKE: &[u8] = b"\x13BitTorrent protocol";
+/// SMTP relay, the port mail is sent from server to server on.
+const SMTP_PORT: u16 = 25;
+/// Submissions (implicit TLS), submission and its common alternative. Mail
+/// clients authenticate there, so these are left alone by default.
+const SUBMISSION_PORTS: [u16; 3] = [465, 587, 2525];
+/// QUIC v1 (RFC 9000) and v2 (RFC 9369) versions.
+const QUIC_VERSIONS: [u32; 2] = [0x0000_0001, 0x6b33_43cf];
+/// Clients pad Initial packets to at least 1200 bytes (RFC 9000 14.1).
+const QUIC_MIN_INITIAL: usize = 1200;
+
+const HTTP_METHODS: [&str; 9] = [
+    "GET", "POST", "HEAD", "PUT", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppProtocol {
+    Tls,
+    Http,
+    BitTorrent,
+    Smtp,
+    Quic,
+}
+
+impl fmt::Display for AppProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tls => write!(f, "tls"),
+            Self::Http => write!(f, "http"),
+            Self::BitTorrent => write!(f, "bittorrent"),
+            Self::Smtp => write!(f, "smtp"),
+            Self::Quic => write!(f, "quic"),
+        }
+    }
+}
+
+impl FromStr for AppProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "tls" => Ok(Self::Tls),
+            "http" => Ok(Self::Http),
+            "bittorrent" | "bt" => Ok(Self::BitTorrent),
+            "smtp" => Ok(Self::Smtp),
+            "quic" => Ok(Self::Quic),
+            _ => Err(format!("invalid app protocol: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sniffed {
+    pub protocol: AppProtocol,
+    pub domain: String,
+}
+
+/// Extracts the destination domain from a client's first payload: the SNI of
+/// a TLS ClientHello or the Host header of a plain HTTP request. IP literals
+/// are ignored since they add nothing to the destination the client sent.
+pub fn sniff(payload: &[u8]) -> Option<Sniffed> {
+    let (protocol, domain) = if let Some(domain) = tls_server_name(payload) {
+        (AppProtocol::Tls, domain)
+    } else {
+        (AppProtocol::Http, http_host(payload)?)
+    };
+
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    if domain.is_empty() || domain.parse::<IpAddr>().is_ok() || !is_hostname(&domain) {
+        return None;
+    }
+    Some(Sniffed { protocol, domain })
+}
+
+/// Classifies a client's first payload for the protocol policy. Mail relay is
+/// also recognized by port 25, since SMTP clients wait for the server banner
+/// before sending anything.
+pub fn classify(payload: &[u8], port: u16, network: Network) -> Option<AppProtocol> {
+    match network {
+        Network::Tcp if payload.starts_with(BITTORRENT_HANDSHAKE) => Some(AppProtocol::BitTorrent),
+        Network::Tcp if port == SMTP_PORT || is_smtp_command(payload) => Some(AppProtocol::Smtp),
+        Network::Tcp if tls_server_name(payload).is_some() => Some(AppProtocol::Tls),
+        Network::Tcp if http_host(payload).is_some() => Some(AppProtocol::Http),
+        Network::Udp if is_dht(payload) => Some(AppProtocol::BitTorrent),
+        Network::Udp if is_quic_initial(payload) => Some(AppProtocol::Quic),
+        _ => None,
+    }
+}
+
+/// Recognizes an SMTP greeting (RFC 5321 4.2) in the first server response.
+/// Greetings on the submission ports don't count.
+pub fn is_smtp_banner(response: &[u8], port: u16) -> bool {
+    if SUBMISSION_PORTS.contains(&port) {
+        return false;
+    }
+    let line = response.split(|x| *x == b'\n').next().unwrap_or_default();
+    (line.starts_with(b"220 ") || line.starts_with(b"220-"))
+        && line.windows(4).any(|x| x.eq_ignore_ascii_case(b"smtp"))
+}
+
+fn is_smtp_command(payload: &[u8]) -> bool {
+    payload.len() > 5
+        && (payload[..5].eq_ignore_ascii_case(b"EHLO ")
+            || payload[..5].eq_ignore_ascii_case(b"HELO "))
+}
+
+/// Mainline DHT queries are bencoded dictionaries such as `d1:ad2:id20:...`.
+fn is_dht(payload: &[u8]) -> bool {
+    payload.starts_with(b"d1:")
+        && payload.ends_with(b"e")
+        && payload.windows(5).any(|x| x == b"1:y1:")
+}
+
+/// Matches the long header of a QUIC Initial packet, which is sent in
+/// cleartext apart from its protected payload.
+fn is_quic_initial(payload: &[u8]) -> bool {
+    if payload.len() < QUIC_MIN_INITIAL || payload[0] & 0xc0 != 0xc0 {
+        return false;
+    }
+    let version = u32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]);
+    QUIC_VERSIONS.contains(&version)
+}
+
+fn is_hostname(domain: &str) -> bool {
+    domain
+        .bytes()
+        .all(|x| x.is_ascii_alphanumeric() || x == b'-' || x == b'.' || x == b'_')
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|x| u16::from_be_bytes([x[0], x[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|x| (x[0] as usize) << 16 | (x[1] as usize) << 8 | x[2] as usize)
+    }
+
+    /// Reads a length-prefixed vector, the length being `n` bytes wide.
+    fn vec(&mut self, n: usize) -> Option<Reader<'a>> {
+        let len = match n {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => self.u24()?,
+        };
+        self.take(len).map(|buf| Reader { buf })
+    }
+}
+
+/// Parses the server_name extension (RFC 6066 3) out of a ClientHello that
+/// fits in the first TLS record.
+fn tls_server_name(payload: &[u8]) -> Option<String> {
+    let mut record = Reader { buf: payload };
+    if record.u8()? != TLS_HANDSHAKE {
+        return None;
+    }
+    record.take(2)?; // legacy record version
+    let mut record = record.vec(2)?;
+
+    if record.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    let mut hello = record.vec(3)?;
+    hello.take(2 + 32)?; // client version, random
+    hello.vec(1)?; // session id
+    hello.vec(2)?; // cipher suites
+    hello.vec(1)?; // compression methods
+
+    let mut extensions = hello.vec(2)?;
+    while !extensions.buf.is_empty() {
+        let kind = extensions.u16()?;
+        let mut data = extensions.vec(2)?;
+        if kind != TLS_EXT_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = data.vec(2)?;
+        while !names.buf.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec(2)?;
+            if name_type == 0 {
+                return String::from_utf8(name.buf.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+/// Returns the Host header of an HTTP/1.x request, without its port.
+fn http_host(payload: &[u8]) -> Option<String> {
+    let method = payload.split(|x| *x == b' ').next()?;
+    if !HTTP_METHODS.iter().any(|x| x.as_bytes() == method) {
+        return None;
+    }
+
+    let head = std::str::from_utf8(payload).ok().or_else(|| {
+        let end = payload.windows(4).position(|x| x == b"\r\n\r\n")?;
+        std::str::from_utf8(&payload[..end]).ok()
+    })?;
+    let head = head.split("\r\n\r\n").next()?;
+
+    let host = head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("host")
+            .then(|| value.trim())
+    })?;
+
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => host.rsplit_once(':').map_or(host, |(host, _)| host),
+    };
+    Some(host.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let name = sni.as_bytes();
+        let mut server_name = vec![0, 0];
+        server_name.extend_from_slice(&((name.len() + 5) as u16).to_be_bytes());
+        server_name.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        server_name.push(0);
+        server_name.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        server_name.extend_from_slice(name);
+
+        // supported_versions before server_name, as browsers send them
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+        extensions.extend_from_slice(&server_name);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[7; 32]);
+        hello.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![TLS_CLIENT_HELLO, 0];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![TLS_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_sniff_tls() {
+        let sniffed = sniff(&client_hello("WWW.Example.com")).unwrap();
+        assert_eq!(sniffed.protocol, AppProtocol::Tls);
+        assert_eq!(sniffed.domain, "www.example.com");
+
+        let hello = client_hello("www.example.com");
+        assert_eq!(sniff(&hello[..hello.len() - 4]), None);
+        assert_eq!(sniff(&client_hello("1.2.3.4")), None);
+    }
+
+    #[test]
+    fn test_sniff_http() {
+        let sniffed =
+            sniff(b"GET / HTTP/1.1\r\nUser-Agent: curl\r\nhost: example.com:8080\r\n\r\n");
+        assert_eq!(
+            sniffed,
+            Some(Sniffed {
+                protocol: AppProtocol::Http,
+                domain: "example.com".to_string()
+            })
+        );
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"), None);
+        assert_eq!(sniff(b"SSH-2.0-OpenSSH_9.6\r\n"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn test_classify() {
+        let mut handshake = BITTORRENT_HANDSHAKE.to_vec();
+        handshake.extend_from_slice(&[0; 48]);
+        assert_eq!(
+            classify(&handshake, 6881, Network::Tcp),
+            Some(AppProtocol::BitTorrent)
+        );
+        assert_eq!(classify(b"", 25, Network::Tcp), Some(AppProtocol::Smtp));
+        assert_eq!(classify(b"", 587, Network::Tcp), None);
+        assert_eq!(
+            classify(b"EHLO mail.example\r\n", 2526, Network::Tcp),
+            Some(AppProtocol::Smtp)
+        );
+        assert_eq!(
+            classify(&client_hello("example.com"), 443, Network::Tcp),
+            Some(AppProtocol::Tls)
+        );
+        assert_eq!(classify(b"\x00\x01", 443, Network::Tcp), None);
+
+        let dht = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(
+            classify(dht, 6881, Network::Udp),
+            Some(AppProtocol::BitTorrent)
+        );
+
+        let mut initial = vec![0xc3, 0, 0, 0, 1];
+        initial.resize(QUIC_MIN_INITIAL, 0);
+        assert_eq!(
+            classify(&initial, 443, Network::Udp),
+            Some(AppProtocol::Quic)
+        );
+        assert_eq!(classify(&initial[..100], 443, Network::Udp), None);
+
+        let banner = b"220 mx.example.com ESMTP Postfix\r\n";
+        assert!(is_smtp_banner(banner, 25));
+        assert!(is_smtp_banner(banner, 8025));
+        assert!(!is_smtp_banner(banner, 587));
+        assert!(!is_smtp_banner(b"220 ProFTPD Server ready\r\n", 21));
+    }
+}
@@ -0,0 +1,171 @@
+use super::error::{ProxyError, Result};
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// A socks5 server, written as `[user:pass@]host:port`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SocksServer {
+    pub host: String,
+    pub port: u16,
+    pub auth: Option<(String, String)>,
+}
+
+impl FromStr for SocksServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid socks server: {}", s);
+        let (auth, addr) = match s.trim().rsplit_once('@') {
+            Some((auth, addr)) => {
+                let (user, pass) = auth.split_once(':').ok_or_else(invalid)?;
+                (Some((user.to_string(), pass.to_string())), addr)
+            }
+            None => (None, s.trim()),
+        };
+        let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+
+        Ok(Self {
+            host: host.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+            auth,
+        })
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc1928
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    server: &SocksServer,
+    addr: &str,
+    port: u16,
+) -> Result<()> {
+    let failed = |e: &str| ProxyError::Connect(e.to_string());
+    let io = |e: std::io::Error| failed(&format!("socks server: {}", e));
+    let method = if server.auth.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await.map_err(io)?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await.map_err(io)?;
+    if choice[0] != 0x05 || choice[1] != method {
+        return Err(failed("socks server rejected auth method"));
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc1929
+    if let Some((user, pass)) = &server.auth {
+        let mut req = vec![0x01];
+        push_field(&mut req, "socks username", user)?;
+        push_field(&mut req, "socks password", pass)?;
+        stream.write_all(&req).await.map_err(io)?;
+
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await.map_err(io)?;
+        if status[1] != 0x00 {
+            return Err(failed("socks authentication failed"));
+        }
+    }
+
+    let mut req = vec![0x05, 0x01, 0x00];
+    match addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(0x01);
+            req.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            req.push(0x04);
+            req.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            req.push(0x03);
+            push_field(&mut req, "socks target domain", addr)?;
+        }
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&req).await.map_err(io)?;
+
+    // +-----+-----+-------+------+----------+----------+
+    // | VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+    // +-----+-----+-------+------+----------+----------+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.map_err(io)?;
+    if reply[1] != 0x00 {
+        return Err(failed(&format!("socks connect failed: {}", reply[1])));
+    }
+    let bound_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await.map_err(io)? as usize,
+        _ => return Err(failed("invalid socks reply")),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await.map_err(io)?;
+
+    Ok(())
+}
+
+/// Appends a field with its one byte length prefix, failing when it doesn't
+/// fit rather than sending a truncated length.
+fn push_field(req: &mut Vec<u8>, name: &str, value: &str) -> Result<()> {
+    let len = u8::try_from(value.len())
+        .map_err(|_| ProxyError::Connect(format!("{} longer than 255 bytes", name)))?;
+    req.push(len);
+    req.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        let server: SocksServer = "alice:secret@127.0.0.1:1080".parse().unwrap();
+        assert_eq!(server.port, 1080);
+        assert!("127.0.0.1".parse::<SocksServer>().is_err());
+
+        let (mut client, mut remote) = tokio::io::duplex(1024);
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let serve = async {
+                let mut greeting = [0u8; 3];
+                remote.read_exact(&mut greeting).await.unwrap();
+                assert_eq!(greeting, [5, 1, 2]);
+                remote.write_all(&[5, 2]).await.unwrap();
+
+                let mut auth = [0u8; 14];
+                remote.read_exact(&mut auth).await.unwrap();
+                assert_eq!(&auth, b"\x01\x05alice\x06secret");
+                remote.write_all(&[1, 0]).await.unwrap();
+
+                let mut connect = [0u8; 18];
+                remote.read_exact(&mut connect).await.unwrap();
+                assert_eq!(&connect, b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+                remote.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            };
+
+            let (res, _) = futures_util::future::join(
+                handshake(&mut client, &server, "example.com", 443),
+                serve,
+            )
+            .await;
+            assert!(res.is_ok());
+        });
+
+        // lengths that don't fit their one byte prefix are refused
+        let long = "a".repeat(256);
+        let server: SocksServer = format!("{}:secret@127.0.0.1:1080", long).parse().unwrap();
+        let (mut client, mut remote) = tokio::io::duplex(1024);
+        rt.block_on(async {
+            remote.write_all(&[5, 2]).await.unwrap();
+            let res = handshake(&mut client, &server, "example.com", 443).await;
+            assert!(matches!(res, Err(ProxyError::Connect(_))));
+
+            let server: SocksServer = "127.0.0.1:1080".parse().unwrap();
+            remote.write_all(&[5, 0]).await.unwrap();
+            let res = handshake(&mut client, &server, &long, 443).await;
+            assert!(matches!(res, Err(ProxyError::Connect(_))));
+        });
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::future::{select, Either};
+use worker::{Date, Delay};
+
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+pub const DEFAULT_MAX_LIFETIME: Duration = Duration::from_secs(4 * 3600);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timeout {
+    Handshake,
+    Connect,
+    Idle,
+    Lifetime,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Handshake => write!(f, "handshake timeout"),
+            Self::Connect => write!(f, "connect timeout"),
+            Self::Idle => write!(f, "idle timeout"),
+            Self::Lifetime => write!(f, "session lifetime exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+impl From<Timeout> for io::Error {
+    fn from(timeout: Timeout) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, timeout)
+    }
+}
+
+/// Session timeouts; a zero duration disables the timeout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// From accepting the websocket until the protocol header is parsed.
+    pub handshake: Duration,
+    /// For each outbound connection attempt.
+    pub connect: Duration,
+    /// Without bytes in either direction.
+    pub idle: Duration,
+    /// Total session duration.
+    pub lifetime: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: DEFAULT_HANDSHAKE_TIMEOUT,
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            idle: DEFAULT_IDLE_TIMEOUT,
+            lifetime: DEFAULT_MAX_LIFETIME,
+        }
+    }
+}
+
+/// Runs `fut` to completion, or fails with `timeout` once `duration` elapses.
+pub async fn with_timeout<F: Future>(
+    duration: Duration,
+    timeout: Timeout,
+    fut: F,
+) -> Result<F::Output, Timeout> {
+    match timer(duration) {
+        Some(timer) => match select(Box::pin(fut), timer).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(timeout),
+        },
+        None => Ok(fut.await),
+    }
+}
+
+fn timer(duration: Duration) -> Option<Pin<Box<Delay>>> {
+    (!duration.is_zero()).then(|| Box::pin(Delay::from(duration)))
+}
+
+/// Running deadlines of a session, polled from its websocket read path so
+/// they fire even while both sides are silent.
+pub struct Deadlines {
+    handshake: Option<Pin<Box<Delay>>>,
+    lifetime: Option<Pin<Box<Delay>>>,
+    idle: Duration,
+    idle_timer: Option<Pin<Box<Delay>>>,
+    last_activity: u64,
+}
+
+impl Deadlines {
+    pub fn new(timeouts: &Timeouts) -> Self {
+        Self {
+            handshake: timer(timeouts.handshake),
+            lifetime: timer(timeouts.lifetime),
+            idle: timeouts.idle,
+            idle_timer: timer(timeouts.idle),
+            last_activity: Date::now().as_millis(),
+        }
+    }
+
+    pub fn handshake_done(&mut self) {
+        self.handshake = None;
+    }
+
+    /// Records traffic in either direction, postponing the idle timeout.
+    pub fn touch(&mut self) {
+        self.last_activity = Date::now().as_millis();
+    }
+
+    pub fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<Timeout> {
+        if let Some(timer) = &mut self.handshake {
+            if timer.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Timeout::Handshake);
+            }
+        }
+        if let Some(timer) = &mut self.lifetime {
+            if timer.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Timeout::Lifetime);
+            }
+        }
+
+        // the idle timer is re-armed lazily rather than on every byte
+        if let Some(timer) = &mut self.idle_timer {
+            while timer.as_mut().poll(cx).is_ready() {
+                let now = Date::now().as_millis();
+                let idle_for = Duration::from_millis(now.saturating_sub(self.last_activity));
+                if idle_for >= self.idle {
+                    return Poll::Ready(Timeout::Idle);
+                }
+                *timer = Box::pin(Delay::from(self.idle - idle_for));
+            }
+        }
+        Poll::Pending
+    }
+}
@@ -1,13 +1,24 @@
+use super::error::{ProxyError, Result};
+use super::privacy::Dest;
+use super::session::Protocol;
 use super::ProxyStream;
+use crate::common::hash::constant_time_eq;
 
+use sha2::{Digest, Sha224};
 use tokio::io::AsyncReadExt;
-use worker::*;
 
 impl <'a> ProxyStream<'a> {
     pub async fn process_trojan(&mut self) -> Result<()> {
-        // ignore user_id
-        let mut _user_id = [0u8; 56];
-        self.read_exact(&mut _user_id).await?;
+        self.session.protocol = Protocol::Trojan;
+
+        // hex encoded sha224 of the password, which is the uuid
+        let mut password_hash = [0u8; 56];
+        self.read_exact(&mut password_hash).await?;
+        let expected = Sha224::digest(self.config.uuid.to_string().as_bytes());
+        password_hash.make_ascii_lowercase();
+        if !constant_time_eq(&password_hash, hex(&expected).as_bytes()) {
+            return Err(ProxyError::Auth(Protocol::Trojan));
+        }
 
         // remove crlf
         self.read_u16().await?;
@@ -27,26 +38,15 @@ impl <'a> ProxyStream<'a> {
         // remove crlf
         self.read_u16().await?;
 
-        console_log!("connecting to upstream {}:{} [is_tcp={is_tcp}]", remote_addr, remote_port);
-
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
-            // send header
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
-            }
-        } else {
-            if let Err(e) = self.handle_udp_outbound().await {
-                console_error!("error handling udp: {}", e)
-            }
-        }
+        crate::log_debug!(
+            "connecting to upstream {} [is_tcp={is_tcp}]",
+            Dest::new(&remote_addr, remote_port)
+        );
 
-        Ok(())
+        self.handle_outbound(remote_addr, remote_port, is_tcp).await
     }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
 }
\ No newline at end of file
@@ -1,18 +1,26 @@
+use super::error::{ProxyError, Result};
+use super::privacy::Dest;
+use super::session::Protocol;
 use super::ProxyStream;
+use crate::common::hash::constant_time_eq;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use uuid::Uuid;
-use worker::*;
 
 impl <'a> ProxyStream<'a> {
     pub async fn process_vless(&mut self) -> Result<()> {
+        self.session.protocol = Protocol::Vless;
+
         // ignore version
         self.read_u8().await?;
         
         // read uuid
         let mut user_id = [0u8; 16];
         self.read_exact(&mut user_id).await?;
-        let _uuid = Uuid::from_bytes(user_id);
+        self.session.user = Uuid::from_bytes(user_id).to_string();
+        if !constant_time_eq(&user_id, self.config.uuid.as_bytes()) {
+            return Err(ProxyError::Auth(Protocol::Vless));
+        }
         
         // read protobuf
         let m_len = self.read_u8().await?;
@@ -31,27 +39,16 @@ impl <'a> ProxyStream<'a> {
         };
         let remote_addr = crate::common::parse_addr(self).await?;
 
-        console_log!("connecting to upstream {}:{} [is_tcp={is_tcp}]", remote_addr, remote_port);
+        crate::log_debug!(
+            "connecting to upstream {} [is_tcp={is_tcp}]",
+            Dest::new(&remote_addr, remote_port)
+        );
 
         if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
             // send header
-            self.write(&[0u8; 2]).await?;
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
-            }
-        } else {
-            if let Err(e) = self.handle_udp_outbound().await {
-                console_error!("error handling udp: {}", e)
-            }
+            self.write_all(&[0u8; 2]).await?;
         }
 
-        Ok(())
+        self.handle_outbound(remote_addr, remote_port, is_tcp).await
     }
 }
\ No newline at end of file
@@ -1,3 +1,6 @@
+use super::error::{ProxyError, Result};
+use super::privacy::Dest;
+use super::session::Protocol;
 use super::ProxyStream;
 
 use crate::common::{
@@ -16,7 +19,6 @@ use aes_gcm::{
 use md5::{Digest, Md5};
 use sha2::Sha256;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use worker::*;
 
 
 impl <'a> ProxyStream<'a> {
@@ -64,7 +66,7 @@ impl <'a> ProxyStream<'a> {
 
             let len = Aes128Gcm::new(header_length_key.into())
                 .decrypt(header_length_nonce.into(), payload)
-                .map_err(|e| Error::RustError(e.to_string()))?;
+                .map_err(|_| ProxyError::Auth(Protocol::Vmess))?;
 
             ((len[0] as u16) << 8) | (len[1] as u16)
         };
@@ -94,13 +96,15 @@ impl <'a> ProxyStream<'a> {
 
             Aes128Gcm::new(payload_key.into())
                 .decrypt(payload_nonce.into(), payload)
-                .map_err(|e| Error::RustError(e.to_string()))?
+                .map_err(|_| ProxyError::Auth(Protocol::Vmess))?
         };
 
         Ok(header_payload)
     }
 
     pub async fn process_vmess(&mut self) -> Result<()> {
+        self.session.protocol = Protocol::Vmess;
+
         let mut buf = Cursor::new(self.aead_decrypt().await?);
 
         // https://xtls.github.io/en/development/protocols/vmess.html#command-section
@@ -113,7 +117,7 @@ impl <'a> ProxyStream<'a> {
 
         let version = buf.read_u8().await?;
         if version != 1 {
-            return Err(Error::RustError("invalid version".to_string()));
+            return Err(ProxyError::Protocol(format!("vmess version {}", version)));
         }
 
         let mut iv = [0u8; 16];
@@ -135,23 +139,26 @@ impl <'a> ProxyStream<'a> {
         };
         let remote_addr = crate::common::parse_addr(&mut buf).await?;
 
-        console_log!("connecting to upstream {}:{} [is_tcp={is_tcp}]", remote_addr, remote_port);
+        crate::log_debug!(
+            "connecting to upstream {} [is_tcp={is_tcp}]",
+            Dest::new(&remote_addr, remote_port)
+        );
 
         // encrypt payload
         let key = &crate::sha256!(&key)[..16];
         let iv = &crate::sha256!(&iv)[..16];
 
         // https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L196
-        let length_key = &hash::kdf(&key, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY])[..16];
-        let length_iv = &hash::kdf(&iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV])[..12];
+        let length_key = &hash::kdf(key, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY])[..16];
+        let length_iv = &hash::kdf(iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV])[..12];
         let length = Aes128Gcm::new(length_key.into())
             // 4 bytes header: https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L238
             .encrypt(length_iv.into(), &4u16.to_be_bytes()[..])
-            .map_err(|e| Error::RustError(e.to_string()))?;
-        self.write(&length).await?;
+            .map_err(|e| ProxyError::Protocol(e.to_string()))?;
+        self.write_all(&length).await?;
 
-        let payload_key = &hash::kdf(&key, &[KDFSALT_CONST_AEAD_RESP_HEADER_KEY])[..16];
-        let payload_iv = &hash::kdf(&iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_IV])[..12];
+        let payload_key = &hash::kdf(key, &[KDFSALT_CONST_AEAD_RESP_HEADER_KEY])[..16];
+        let payload_iv = &hash::kdf(iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_IV])[..12];
         let header = {
             let header = [
                 options[0], // https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L242
@@ -159,27 +166,10 @@ impl <'a> ProxyStream<'a> {
             ];
             Aes128Gcm::new(payload_key.into())
                 .encrypt(payload_iv.into(), &header[..])
-                .map_err(|e| Error::RustError(e.to_string()))?
+                .map_err(|e| ProxyError::Protocol(e.to_string()))?
         };
-        self.write(&header).await?;
-
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
-                }
-            }
-        } else {
-            if let Err(e) = self.handle_udp_outbound().await {
-                console_error!("error handling udp: {}", e)
-            }
-        }
+        self.write_all(&header).await?;
 
-        Ok(())
+        self.handle_outbound(remote_addr, remote_port, is_tcp).await
     }
 }
\ No newline at end of file
@@ -0,0 +1,115 @@
+use crate::config::Config;
+use crate::proxy::dns::resolver::Resolver;
+use crate::proxy::outbound;
+use crate::proxy::routing::Routing;
+use crate::proxy::session::{Network, Protocol};
+
+use std::future::Future;
+
+use futures_util::future::join_all;
+use serde::Serialize;
+use worker::Date;
+
+/// Result of one health check.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub target: String,
+    pub ok: bool,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+impl Check {
+    async fn run<E: ToString>(target: String, check: impl Future<Output = Result<(), E>>) -> Self {
+        let started = Date::now().as_millis();
+        let res = check.await;
+        Self {
+            target,
+            ok: res.is_ok(),
+            latency_ms: Date::now().as_millis().saturating_sub(started),
+            error: res.err().map(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Pool {
+    pub name: String,
+    pub members: Vec<Check>,
+}
+
+/// The `/status` report.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub version: &'static str,
+    pub protocols: Vec<String>,
+    pub transports: Vec<String>,
+    pub users: usize,
+    /// Proxy pools and the default proxy, each member probed live by this
+    /// request. No results are kept between requests.
+    pub proxy_probes: Vec<Pool>,
+    pub dns_upstreams: Vec<Check>,
+}
+
+/// Every protocol authenticates with the configured uuid, so they are all
+/// enabled once it is set and none are while it is nil.
+fn enabled_protocols(config: &Config) -> Vec<Protocol> {
+    if config.uuid.is_nil() {
+        return Vec::new();
+    }
+    vec![
+        Protocol::Vless,
+        Protocol::Vmess,
+        Protocol::Trojan,
+        Protocol::Shadowsocks,
+    ]
+}
+
+/// Builds the report, checking the default proxy, every proxy pool member and
+/// dns upstream concurrently.
+pub async fn report(config: &Config, routing: &Routing) -> Status {
+    let timeout = config.timeouts.connect;
+    let default = vec![(config.proxy_addr.clone(), config.proxy_port)];
+    let mut targets: Vec<_> = routing.pools.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    targets.sort_by_key(|(name, _)| *name);
+    targets.insert(0, ("default", &default));
+    let pools = targets.into_iter().map(|(name, members)| async move {
+        let members = members.iter().map(|(host, port)| {
+            Check::run(
+                format!("{}:{}", host, port),
+                outbound::check(host, *port, timeout),
+            )
+        });
+        Pool {
+            name: name.to_string(),
+            members: join_all(members).await,
+        }
+    });
+
+    let resolver = Resolver::new(
+        config.dns_upstreams.clone(),
+        config.dns_rules.clone(),
+        config.dns_timeout,
+    );
+    let upstreams = config
+        .dns_upstreams
+        .iter()
+        .map(|upstream| Check::run(upstream.to_string(), resolver.check(upstream)));
+
+    let (proxy_probes, dns_upstreams) = futures_util::join!(join_all(pools), join_all(upstreams));
+    Status {
+        version: env!("CARGO_PKG_VERSION"),
+        protocols: enabled_protocols(config)
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        // udp is only served for dns
+        transports: [Network::Tcp, Network::Udp]
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        users: usize::from(!config.uuid.is_nil()),
+        proxy_probes,
+        dns_upstreams,
+    }
+}